@@ -1,55 +1,29 @@
-use {std::io::Cursor, std::sync::mpsc, tray_item::IconSource, tray_item::TrayItem};
-
-enum Message {
-    Quit,
-    Update,
-}
+use tray_item::{IconSource, TrayEvent, TrayItem};
 
 fn main() {
-    let cursor_red = Cursor::new(include_bytes!("../../resources/tray_icon-red.png"));
-    let decoder_red = png::Decoder::new(cursor_red);
-    let (info_red, mut reader_red) = decoder_red.read_info().unwrap();
-    let mut buf_red = vec![0; info_red.buffer_size()];
-    reader_red.next_frame(&mut buf_red).unwrap();
-
-    let icon_red = IconSource::Data {
-        data: buf_red,
-        height: 32,
-        width: 32,
-    };
+    let icon_red =
+        IconSource::from_png_bytes(include_bytes!("../../resources/tray_icon-red.png")).unwrap();
 
     let mut tray = TrayItem::new("Tray Example", icon_red).unwrap();
 
     tray.add_label("Tray Label").unwrap();
 
-    let (tx, rx) = mpsc::sync_channel::<Message>(2);
-    let update_tx = tx.clone();
-    let id_menu = tray
-        .inner_mut()
-        .add_menu_item_with_id("Update Menu Item", move || {
-            update_tx.send(Message::Update).unwrap();
-        })
-        .unwrap();
-
-    let quit_tx = tx.clone();
-    tray.add_menu_item("Quit", move || {
-        quit_tx.send(Message::Quit).unwrap();
-    })
-    .unwrap();
+    let id_menu = tray.add_item("Update Menu Item").unwrap();
+    let id_quit = tray.add_item("Quit").unwrap();
 
+    let events = tray.events();
     loop {
-        match rx.recv() {
-            Ok(Message::Quit) => {
+        match events.recv() {
+            Ok(TrayEvent::MenuItemActivated(id)) if id == id_quit => {
                 println!("Quit");
                 break;
             }
-            Ok(Message::Update) => {
+            Ok(TrayEvent::MenuItemActivated(id)) if id == id_menu => {
                 println!("Update Menu Item!");
-                tray.inner_mut()
-                    .set_menu_item_label("Menu Updated", id_menu)
-                    .unwrap();
+                tray.set_menu_item_label("Menu Updated", id_menu).unwrap();
             }
-            _ => {}
+            Ok(_) => {}
+            Err(_) => break,
         }
     }
 }
@@ -1,5 +1,4 @@
 use {
-    std::io::Cursor,
     std::sync::mpsc,
     tray_item::TrayItem,
     tray_item::IconSource
@@ -12,13 +11,7 @@ enum Message {
 }
 
 fn main() {
-    let cursor_red = Cursor::new(include_bytes!("../../resources/tray_icon-red.png"));
-    let decoder_red = png::Decoder::new(cursor_red);
-    let (info_red, mut reader_red) = decoder_red.read_info().unwrap();
-    let mut buf_red = vec![0;info_red.buffer_size()];
-    reader_red.next_frame(&mut buf_red).unwrap();
-
-    let icon_red = IconSource::Data{data: buf_red, height: 32, width: 32};
+    let icon_red = IconSource::from_png_bytes(include_bytes!("../../resources/tray_icon-red.png")).unwrap();
 
     let mut tray = TrayItem::new("Tray Example", icon_red).unwrap();
 
@@ -51,22 +44,12 @@ fn main() {
             },
             Ok(Message::Green) =>{
                 println!("Green!");
-                let cursor_green = Cursor::new(include_bytes!("../../resources/tray_icon-green.png"));
-                let decoder_green = png::Decoder::new(cursor_green);
-                let (info_green, mut reader_green) = decoder_green.read_info().unwrap();
-                let mut buf_green = vec![0;info_green.buffer_size()];
-                reader_green.next_frame(&mut buf_green).unwrap();
-                let icon_green = IconSource::Data{data: buf_green, height: 32, width: 32};
+                let icon_green = IconSource::from_png_bytes(include_bytes!("../../resources/tray_icon-green.png")).unwrap();
                 tray.set_icon(icon_green).unwrap();
             },
             Ok(Message::Red) => {
                 println!("Red!");
-                let cursor_red = Cursor::new(include_bytes!("../../resources/tray_icon-red.png"));
-                let decoder_red = png::Decoder::new(cursor_red);
-                let (info_red, mut reader_red) = decoder_red.read_info().unwrap();
-                let mut buf_red = vec![0;info_red.buffer_size()];
-                reader_red.next_frame(&mut buf_red).unwrap();
-                let icon_red = IconSource::Data{data: buf_red, height: 32, width: 32};
+                let icon_red = IconSource::from_png_bytes(include_bytes!("../../resources/tray_icon-red.png")).unwrap();
                 tray.set_icon(icon_red).unwrap();
             },
             _ => {}
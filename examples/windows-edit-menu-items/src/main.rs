@@ -28,9 +28,9 @@ fn main() {
     )
     .unwrap();
 
-    let label_id = tray.inner_mut().add_label_with_id("Tray Label").unwrap();
+    let label_id = tray.add_label("Tray Label").unwrap();
 
-    tray.inner_mut().add_separator().unwrap();
+    tray.add_separator().unwrap();
 
     let (tx, rx) = mpsc::sync_channel(1);
 
@@ -41,13 +41,13 @@ fn main() {
     .unwrap();
 
     let color_tx = tx.clone();
-    let color_id = tray.inner_mut().add_menu_item_with_id("Change to Red", move || {
+    let color_id = tray.add_menu_item("Change to Red", move || {
         color_tx.send(Message::ChangeIcon).unwrap();
     })
     .unwrap();
     let mut current_icon = Icon::Green;
 
-    tray.inner_mut().add_separator().unwrap();
+    tray.add_separator().unwrap();
 
     let quit_tx = tx.clone();
     tray.add_menu_item("Quit", move || {
@@ -68,12 +68,12 @@ fn main() {
                 };
                 current_icon = next_icon;
 
-                tray.inner_mut().set_menu_item_label(next_message, color_id).unwrap();
+                tray.set_menu_item_label(next_message, color_id).unwrap();
                 tray.set_icon(current_icon.resource())
                     .unwrap();
             },
             Ok(Message::Hello) => {
-                tray.inner_mut().set_label("Hi there!", label_id).unwrap();
+                tray.set_menu_item_label("Hi there!", label_id).unwrap();
             },
             _ => {}
         }
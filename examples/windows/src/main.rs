@@ -21,7 +21,7 @@ fn main() {
     })
     .unwrap();
 
-    tray.inner_mut().add_separator().unwrap();
+    tray.add_separator().unwrap();
 
     let (tx, rx) = mpsc::sync_channel(1);
 
@@ -37,7 +37,7 @@ fn main() {
     })
     .unwrap();
 
-    tray.inner_mut().add_separator().unwrap();
+    tray.add_separator().unwrap();
 
     let quit_tx = tx.clone();
     tray.add_menu_item("Quit", move || {
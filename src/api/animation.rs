@@ -0,0 +1,69 @@
+// Animated icons for the backends that show the frames themselves
+
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+// Frames are shared with the thread driving them, which only moves `frame`
+#[cfg(target_os = "linux")]
+pub(crate) struct Animation<T> {
+    frames: Arc<Vec<T>>,
+    frame: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl<T> Animation<T> {
+    pub(crate) fn new(frames: Vec<T>) -> Self {
+        Self {
+            frames: Arc::new(frames),
+            frame: 0,
+        }
+    }
+
+    pub(crate) fn frames(&self) -> Arc<Vec<T>> {
+        Arc::clone(&self.frames)
+    }
+
+    pub(crate) fn current(&self) -> &T {
+        &self.frames[self.frame]
+    }
+}
+
+// Moves the animation on to its next frame, unless it no longer shows `frames`
+// because it was stopped or replaced since. Returns whether it moved.
+#[cfg(target_os = "linux")]
+pub(crate) fn advance<T>(animation: &mut Option<Animation<T>>, frames: &Arc<Vec<T>>) -> bool {
+    match animation {
+        Some(animation) if Arc::ptr_eq(&animation.frames, frames) => {
+            animation.frame = (animation.frame + 1) % frames.len();
+            true
+        }
+        _ => false,
+    }
+}
+
+// Calls `tick` every `interval` on a thread of its own. Dropping it stops the
+// animation thread.
+pub(crate) struct Ticker {
+    _stop: mpsc::Sender<()>,
+}
+
+impl Ticker {
+    pub(crate) fn start<F>(interval: Duration, mut tick: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                tick();
+            }
+        });
+
+        Self { _stop: tx }
+    }
+}
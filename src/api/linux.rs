@@ -0,0 +1,263 @@
+// Every Linux backend that is enabled gets compiled in, `new` picks one when
+// the tray is created
+
+#[cfg(feature = "ksni")]
+use super::linux_ksni;
+#[cfg(feature = "libappindicator")]
+use super::linux_libappindicator;
+#[cfg(feature = "xembed")]
+use super::linux_xembed;
+#[cfg(feature = "zbus")]
+use super::linux_zbus;
+use {
+    crate::{
+        event::IconAction, menu::SharedMenu, Backend, IconSource, TIError, Tooltip, TrayStatus,
+    },
+    std::{path::Path, sync::Arc, time::Duration},
+};
+
+pub enum TrayItemLinux {
+    #[cfg(feature = "ksni")]
+    Ksni(linux_ksni::TrayItemLinux),
+    #[cfg(feature = "zbus")]
+    Zbus(linux_zbus::TrayItemLinux),
+    #[cfg(feature = "libappindicator")]
+    LibAppIndicator(linux_libappindicator::TrayItemLinux),
+    #[cfg(feature = "xembed")]
+    XEmbed(linux_xembed::TrayItemLinux),
+}
+
+// Runs `$call` on whichever backend is in use
+macro_rules! dispatch {
+    ($self:ident, $tray:ident => $call:expr) => {
+        match *$self {
+            #[cfg(feature = "ksni")]
+            Self::Ksni(ref mut $tray) => $call,
+            #[cfg(feature = "zbus")]
+            Self::Zbus(ref mut $tray) => $call,
+            #[cfg(feature = "libappindicator")]
+            Self::LibAppIndicator(ref mut $tray) => $call,
+            #[cfg(feature = "xembed")]
+            Self::XEmbed(ref mut $tray) => $call,
+        }
+    };
+}
+
+// Without any backend the enum is empty and every method unreachable, `new`
+// reports what is missing
+#[cfg_attr(
+    not(any(
+        feature = "ksni",
+        feature = "zbus",
+        feature = "libappindicator",
+        feature = "xembed"
+    )),
+    allow(unused_variables, unreachable_code)
+)]
+impl TrayItemLinux {
+    // Tries the backends in the order `candidates` gives, keeping the first
+    // that starts
+    pub fn new(title: &str, icon: IconSource, menu: SharedMenu) -> Result<Self, TIError> {
+        let mut error = None;
+        for backend in candidates() {
+            match Self::with_backend(title, icon.clone(), Arc::clone(&menu), backend) {
+                Ok(tray) => return Ok(tray),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        Err(error.unwrap_or_else(no_backend))
+    }
+
+    // Same as `new`, the SNI backends connect without blocking the executor
+    #[cfg(all(feature = "async", any(feature = "ksni", feature = "zbus")))]
+    pub async fn new_async(
+        title: &str,
+        icon: IconSource,
+        menu: SharedMenu,
+    ) -> Result<Self, TIError> {
+        let mut error = None;
+        for backend in order(super::sni::watcher_running_async().await) {
+            let tray = match backend {
+                #[cfg(feature = "ksni")]
+                Backend::Ksni => {
+                    linux_ksni::TrayItemLinux::new_async(title, icon.clone(), Arc::clone(&menu))
+                        .await
+                        .map(Self::Ksni)
+                }
+                #[cfg(feature = "zbus")]
+                Backend::Zbus => {
+                    linux_zbus::TrayItemLinux::new_async(title, icon.clone(), Arc::clone(&menu))
+                        .await
+                        .map(Self::Zbus)
+                }
+                #[allow(unreachable_patterns)]
+                _ => Self::with_backend(title, icon.clone(), Arc::clone(&menu), backend),
+            };
+            match tray {
+                Ok(tray) => return Ok(tray),
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        Err(error.unwrap_or_else(no_backend))
+    }
+
+    pub fn with_backend(
+        title: &str,
+        icon: IconSource,
+        menu: SharedMenu,
+        backend: Backend,
+    ) -> Result<Self, TIError> {
+        Ok(match backend {
+            #[cfg(feature = "ksni")]
+            Backend::Ksni => Self::Ksni(linux_ksni::TrayItemLinux::new(title, icon, menu)?),
+            #[cfg(feature = "zbus")]
+            Backend::Zbus => Self::Zbus(linux_zbus::TrayItemLinux::new(title, icon, menu)?),
+            #[cfg(feature = "libappindicator")]
+            Backend::LibAppIndicator => Self::LibAppIndicator(
+                linux_libappindicator::TrayItemLinux::new(title, icon, menu)?,
+            ),
+            #[cfg(feature = "xembed")]
+            Backend::XEmbed => Self::XEmbed(linux_xembed::TrayItemLinux::new(title, icon, menu)?),
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        match *self {
+            #[cfg(feature = "ksni")]
+            Self::Ksni(_) => Backend::Ksni,
+            #[cfg(feature = "zbus")]
+            Self::Zbus(_) => Backend::Zbus,
+            #[cfg(feature = "libappindicator")]
+            Self::LibAppIndicator(_) => Backend::LibAppIndicator,
+            #[cfg(feature = "xembed")]
+            Self::XEmbed(_) => Backend::XEmbed,
+        }
+    }
+
+    pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_icon(icon))
+    }
+
+    pub fn set_animated_icon(
+        &mut self,
+        frames: Vec<IconSource>,
+        interval: Duration,
+    ) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_animated_icon(frames, interval))
+    }
+
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.stop_animation())
+    }
+
+    pub fn set_title_label(&mut self, label: Option<&str>) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_title_label(label))
+    }
+
+    pub fn set_icon_theme_path(&mut self, path: &Path) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_icon_theme_path(path))
+    }
+
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_status(status))
+    }
+
+    pub fn set_overlay_icon(&mut self, icon: Option<IconSource>) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_overlay_icon(icon))
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_attention_icon(icon))
+    }
+
+    pub fn set_attention_movie(&mut self, name: &str) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_attention_movie(name))
+    }
+
+    pub fn set_tooltip(&mut self, tooltip: Tooltip) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.set_tooltip(tooltip))
+    }
+
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.update_menu())
+    }
+
+    pub(crate) fn enable_icon_action(&mut self, action: IconAction) -> Result<(), TIError> {
+        dispatch!(self, tray => tray.enable_icon_action(action))
+    }
+
+    // Only the SNI backends have async versions, the others are quick enough
+    // to call directly
+    #[cfg(all(feature = "async", any(feature = "ksni", feature = "zbus")))]
+    pub async fn set_icon_async(&mut self, icon: IconSource) -> Result<(), TIError> {
+        match *self {
+            #[cfg(feature = "ksni")]
+            Self::Ksni(ref mut tray) => tray.set_icon_async(icon).await,
+            #[cfg(feature = "zbus")]
+            Self::Zbus(ref mut tray) => tray.set_icon_async(icon).await,
+            #[allow(unreachable_patterns)]
+            _ => self.set_icon(icon),
+        }
+    }
+
+    #[cfg(all(feature = "async", any(feature = "ksni", feature = "zbus")))]
+    pub async fn update_menu_async(&mut self) -> Result<(), TIError> {
+        match *self {
+            #[cfg(feature = "ksni")]
+            Self::Ksni(ref mut tray) => tray.update_menu_async().await,
+            #[cfg(feature = "zbus")]
+            Self::Zbus(ref mut tray) => tray.update_menu_async().await,
+            #[allow(unreachable_patterns)]
+            _ => self.update_menu(),
+        }
+    }
+}
+
+fn candidates() -> Vec<Backend> {
+    order(sni_watcher())
+}
+
+// SNI first when a watcher is there to show it, otherwise the backends that
+// work without one. The rest still follow in case those fail to start.
+fn order(watcher: bool) -> Vec<Backend> {
+    let sni: &[Backend] = &[
+        #[cfg(feature = "ksni")]
+        Backend::Ksni,
+        #[cfg(feature = "zbus")]
+        Backend::Zbus,
+    ];
+    let fallback: &[Backend] = &[
+        #[cfg(feature = "libappindicator")]
+        Backend::LibAppIndicator,
+        #[cfg(feature = "xembed")]
+        Backend::XEmbed,
+    ];
+
+    if watcher {
+        [sni, fallback].concat()
+    } else {
+        [fallback, sni].concat()
+    }
+}
+
+#[cfg(any(feature = "ksni", feature = "zbus"))]
+fn sni_watcher() -> bool {
+    super::sni::watcher_running()
+}
+
+#[cfg(not(any(feature = "ksni", feature = "zbus")))]
+fn sni_watcher() -> bool {
+    false
+}
+
+fn no_backend() -> TIError {
+    TIError::new(
+        "No tray backend is enabled, enable the ksni, zbus, libappindicator or xembed feature",
+    )
+}
@@ -1,169 +1,471 @@
-use crate::{IconSource, TIError};
-use ksni::{menu::StandardItem, Handle, Icon};
-use std::sync::{Arc, Mutex};
-
-enum TrayItem {
-    Label(String),
-    MenuItem {
-        id: u32,
-        label: String,
-        action: Arc<dyn Fn() + Send + Sync + 'static>,
+use crate::{
+    api::{
+        animation::{self, Animation, Ticker},
+        sni::{self, Click, Clicks},
     },
-    Separator,
-}
+    event::IconAction,
+    icon::{SniIcon, SniPixmaps},
+    menu::SharedMenu,
+    path_str, IconSource, MenuItem, MenuItemKind, ScrollOrientation, TIError, Tooltip, TrayEvent,
+    TrayStatus,
+};
+use ksni::{
+    menu::{CheckmarkItem, RadioGroup, RadioItem, StandardItem, SubMenu},
+    Handle, Icon, ToolTip,
+};
+
+use std::{path::Path, sync::mpsc, thread, time::Duration};
+
+// ksni has no icon-only update, every frame also rebuilds the whole menu from
+// the shared model to diff it, which is too much work to do 100 times a second
+const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(feature = "async")]
+use futures_channel::oneshot;
 
 struct Tray {
     title: String,
-    icon: IconSource,
-    actions: Vec<TrayItem>,
-    next_id: u32,
+    // Shown instead of the title, SNI has no separate label
+    label: Option<String>,
+    icon: SniIcon,
+    animation: Option<Animation<SniIcon>>,
+    icon_theme_path: String,
+    status: TrayStatus,
+    overlay_icon: SniIcon,
+    attention_icon: SniIcon,
+    attention_movie: String,
+    tooltip: ToolTip,
+    menu: SharedMenu,
+    clicks: Clicks,
+    // Told once the service is connected and registered, see `watcher_online`
+    started: mpsc::Sender<Result<(), TIError>>,
 }
 
 pub struct TrayItemLinux {
     tray: Handle<Tray>,
+    clicks: Clicks,
+    animation: Option<Ticker>,
+    #[cfg(feature = "async")]
+    updates: mpsc::Sender<Update>,
 }
 
+// A change to apply to the tray and the channel to report completion on
+#[cfg(feature = "async")]
+type Update = (Box<dyn FnOnce(&mut Tray) + Send>, oneshot::Sender<()>);
+
 impl ksni::Tray for Tray {
     fn id(&self) -> String {
         self.title.clone()
     }
 
     fn title(&self) -> String {
-        self.title.clone()
+        self.label.as_ref().unwrap_or(&self.title).clone()
     }
 
-    fn icon_name(&self) -> String {
-        match &self.icon {
-            IconSource::Resource(name) => name.to_string(),
-            IconSource::Data { .. } => String::new(),
+    fn status(&self) -> ksni::Status {
+        match self.status {
+            TrayStatus::Active => ksni::Status::Active,
+            TrayStatus::Passive => ksni::Status::Passive,
+            TrayStatus::NeedsAttention => ksni::Status::NeedsAttention,
         }
     }
 
+    fn icon_theme_path(&self) -> String {
+        self.icon_theme_path.clone()
+    }
+
+    fn icon_name(&self) -> String {
+        self.current_icon().name.clone()
+    }
+
     fn icon_pixmap(&self) -> Vec<Icon> {
-        match &self.icon {
-            IconSource::Resource(_) => vec![],
-            IconSource::Data {
-                data,
-                height,
-                width,
-            } => {
-                vec![Icon {
-                    width: *height,
-                    height: *width,
-                    data: data.clone(),
-                }]
-            }
-        }
+        icons(&self.current_icon().pixmaps)
+    }
+
+    fn overlay_icon_name(&self) -> String {
+        self.overlay_icon.name.clone()
+    }
+
+    fn overlay_icon_pixmap(&self) -> Vec<Icon> {
+        icons(&self.overlay_icon.pixmaps)
+    }
+
+    fn attention_icon_name(&self) -> String {
+        self.attention_icon.name.clone()
+    }
+
+    fn attention_icon_pixmap(&self) -> Vec<Icon> {
+        icons(&self.attention_icon.pixmaps)
+    }
+
+    fn attention_movie_name(&self) -> String {
+        self.attention_movie.clone()
+    }
+
+    fn tool_tip(&self) -> ToolTip {
+        self.tooltip.clone()
+    }
+
+    fn activate(&mut self, x: i32, y: i32) {
+        self.clicks
+            .send(Click::Icon(TrayEvent::IconActivated { x, y }))
+            .ok();
+    }
+
+    fn secondary_activate(&mut self, x: i32, y: i32) {
+        self.clicks
+            .send(Click::Icon(TrayEvent::SecondaryActivated { x, y }))
+            .ok();
+    }
+
+    fn scroll(&mut self, delta: i32, dir: &str) {
+        let orientation = ScrollOrientation::from_sni(dir);
+        self.clicks
+            .send(Click::Icon(TrayEvent::Scrolled { delta, orientation }))
+            .ok();
     }
 
     fn menu(&self) -> Vec<ksni::MenuItem<Self>> {
-        self.actions
-            .iter()
-            .map(|item| match item {
-                TrayItem::Label(label) => StandardItem {
-                    label: label.clone(),
-                    enabled: false,
+        build_menu(self.menu.lock().unwrap().items())
+    }
+
+    // ksni calls one of these once it is on the bus and has tried to register
+    // the item, later calls find `new` gone and are ignored
+    fn watcher_online(&self) {
+        self.started.send(Ok(())).ok();
+    }
+
+    fn watcher_offine(&self) -> bool {
+        self.started.send(Ok(())).ok();
+        true
+    }
+}
+
+impl Tray {
+    fn current_icon(&self) -> &SniIcon {
+        match &self.animation {
+            Some(animation) => animation.current(),
+            None => &self.icon,
+        }
+    }
+}
+
+fn icons(pixmaps: &SniPixmaps) -> Vec<Icon> {
+    pixmaps
+        .iter()
+        .map(|(width, height, data)| Icon {
+            width: *width,
+            height: *height,
+            data: data.clone(),
+        })
+        .collect()
+}
+
+fn build_menu(items: &[MenuItem]) -> Vec<ksni::MenuItem<Tray>> {
+    items
+        .iter()
+        // ksni separators have no visibility flag, leave hidden ones out
+        .filter(|item| item.kind() != MenuItemKind::Separator || item.is_visible())
+        .map(|item| match item.kind() {
+            MenuItemKind::Label => StandardItem {
+                label: item.label().to_string(),
+                enabled: item.is_enabled(),
+                visible: item.is_visible(),
+                ..Default::default()
+            }
+            .into(),
+            MenuItemKind::Action => {
+                let id = item.id();
+                StandardItem {
+                    label: item.label().to_string(),
+                    enabled: item.is_enabled(),
+                    visible: item.is_visible(),
+                    activate: Box::new(move |tray: &mut Tray| {
+                        tray.clicks.send(Click::Item(id)).ok();
+                    }),
                     ..Default::default()
                 }
-                .into(),
-                TrayItem::MenuItem { label, action, .. } => {
-                    let action = action.clone();
-                    StandardItem {
-                        label: label.clone(),
-                        activate: Box::new(move |_| {
-                            action();
-                        }),
-                        ..Default::default()
-                    }
-                    .into()
+                .into()
+            }
+            MenuItemKind::Check => {
+                let id = item.id();
+                CheckmarkItem {
+                    label: item.label().to_string(),
+                    enabled: item.is_enabled(),
+                    visible: item.is_visible(),
+                    checked: item.is_checked(),
+                    activate: Box::new(move |tray: &mut Tray| {
+                        tray.clicks.send(Click::Item(id)).ok();
+                    }),
+                    ..Default::default()
                 }
-                TrayItem::Separator => ksni::MenuItem::Separator,
-            })
-            .collect()
-    }
+                .into()
+            }
+            MenuItemKind::RadioGroup => {
+                let id = item.id();
+                RadioGroup {
+                    selected: item.selected(),
+                    select: Box::new(move |tray: &mut Tray, index| {
+                        tray.clicks.send(Click::Option(id, index)).ok();
+                    }),
+                    options: item
+                        .options()
+                        .iter()
+                        .map(|option| RadioItem {
+                            label: option.clone(),
+                            enabled: item.is_enabled(),
+                            visible: item.is_visible(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                }
+                .into()
+            }
+            MenuItemKind::Separator => ksni::MenuItem::Separator,
+            MenuItemKind::Submenu => SubMenu {
+                label: item.label().to_string(),
+                enabled: item.is_enabled(),
+                visible: item.is_visible(),
+                submenu: build_menu(item.children()),
+                ..Default::default()
+            }
+            .into(),
+        })
+        .collect()
 }
 
 impl TrayItemLinux {
-    pub fn new(title: &str, icon: IconSource) -> Result<Self, TIError> {
+    pub fn new(title: &str, icon: IconSource, menu: SharedMenu) -> Result<Self, TIError> {
+        let (clicks, rx) = mpsc::channel();
+        let (started_tx, started) = mpsc::channel();
         let svc = ksni::TrayService::new(Tray {
             title: title.to_string(),
-            icon,
-            actions: vec![],
-            next_id: 0,
+            label: None,
+            icon: SniIcon::new(&icon)?,
+            animation: None,
+            icon_theme_path: String::new(),
+            status: TrayStatus::Active,
+            overlay_icon: SniIcon::default(),
+            attention_icon: SniIcon::default(),
+            attention_movie: String::new(),
+            tooltip: ToolTip::default(),
+            menu: SharedMenu::clone(&menu),
+            clicks: clicks.clone(),
+            started: started_tx.clone(),
         });
 
+        // `TrayService::spawn` panics on its own thread when there is no session
+        // bus, run it here to hand the error to the caller instead
         let handle = svc.handle();
-        svc.spawn();
+        thread::spawn(move || {
+            if let Err(e) = svc.run() {
+                started_tx
+                    .send(Err(TIError::new(format!(
+                        "Failed to start the tray service: {}",
+                        e
+                    ))))
+                    .ok();
+            }
+        });
+        started
+            .recv()
+            .map_err(|_| TIError::new("The tray service has stopped"))??;
+
+        let worker = handle.clone();
+        sni::handle_clicks(rx, menu, move || worker.update(|_| {}));
 
-        Ok(Self { tray: handle })
+        Ok(Self {
+            #[cfg(feature = "async")]
+            updates: spawn_updater(handle.clone()),
+            tray: handle,
+            clicks,
+            animation: None,
+        })
+    }
+
+    // The service reports back from its own thread, which is waited on by
+    // another one instead of the caller's executor
+    #[cfg(feature = "async")]
+    pub async fn new_async(
+        title: &str,
+        icon: IconSource,
+        menu: SharedMenu,
+    ) -> Result<Self, TIError> {
+        let title = title.to_string();
+        sni::unblock(move || Self::new(&title, icon, menu)).await
     }
 
     pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
-        self.tray.update(|tray| tray.icon = icon.clone());
+        let icon = SniIcon::new(&icon)?;
+        self.animation = None;
+        self.tray.update(|tray| {
+            tray.animation = None;
+            tray.icon = icon;
+        });
 
         Ok(())
     }
 
-    pub fn add_label(&mut self, label: &str) -> Result<(), TIError> {
-        self.tray.update(move |tray| {
-            tray.actions.push(TrayItem::Label(label.to_string()));
-        });
+    pub fn set_animated_icon(
+        &mut self,
+        frames: Vec<IconSource>,
+        interval: Duration,
+    ) -> Result<(), TIError> {
+        if interval < MIN_FRAME_INTERVAL {
+            return Err(TIError::new(format!(
+                "ksni needs a frame interval of at least {:?}",
+                MIN_FRAME_INTERVAL
+            )));
+        }
+        let animation = Animation::new(
+            frames
+                .iter()
+                .map(SniIcon::new)
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        let frames = animation.frames();
+        self.tray.update(|tray| tray.animation = Some(animation));
+
+        // Only NewIcon is sent as nothing else changed
+        let handle = self.tray.clone();
+        self.animation = Some(Ticker::start(interval, move || {
+            handle.update(|tray| animation::advance(&mut tray.animation, &frames));
+        }));
 
         Ok(())
     }
 
-    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<(), TIError>
-    where
-        F: Fn() -> () + Send + Sync + 'static,
-    {
-        self.add_menu_item_with_id(label, cb)?;
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        self.animation = None;
+        self.tray.update(|tray| tray.animation = None);
+
         Ok(())
     }
 
-    pub fn add_menu_item_with_id<F>(&mut self, label: &str, cb: F) -> Result<u32, TIError>
-    where
-        F: Fn() + Send + Sync + 'static,
-    {
-        let action = Arc::new(cb);
-        let item_id = Arc::new(Mutex::new(0));
-        let item_id_clone = Arc::clone(&item_id);
-
-        self.tray.update(move |tray| {
-            let mut id = item_id_clone.lock().unwrap();
-            *id = tray.next_id;
-            tray.next_id += 1;
-
-            tray.actions.push(TrayItem::MenuItem {
-                id: *id,
-                label: label.to_string(),
-                action: action.clone(),
-            });
-        });
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
+        // The menu is read from the shared model, this only signals ksni to re-render it
+        self.tray.update(|_| {});
 
-        let final_id = *item_id.lock().unwrap();
-        Ok(final_id)
+        Ok(())
     }
 
-    pub fn set_menu_item_label(&mut self, label: &str, id: u32) -> Result<(), TIError> {
-        self.tray.update(move |tray| {
-            if let Some(item) = tray.actions.iter_mut().find_map(|item| match item {
-                TrayItem::MenuItem {
-                    id: item_id, label, ..
-                } if *item_id == id => Some(label),
-                _ => None,
-            }) {
-                *item = label.to_string();
-            }
-        });
+    pub fn set_title_label(&mut self, label: Option<&str>) -> Result<(), TIError> {
+        let label = label.map(str::to_string);
+        self.tray.update(|tray| tray.label = label);
 
         Ok(())
     }
 
-    pub fn add_separator(&mut self) -> Result<(), TIError> {
-        self.tray.update(move |tray| {
-            tray.actions.push(TrayItem::Separator);
-        });
+    pub fn set_icon_theme_path(&mut self, path: &Path) -> Result<(), TIError> {
+        let path = path_str(path)?.to_string();
+        self.tray.update(|tray| tray.icon_theme_path = path);
+
+        Ok(())
+    }
+
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.tray.update(|tray| tray.status = status);
+
+        Ok(())
+    }
+
+    pub fn set_overlay_icon(&mut self, icon: Option<IconSource>) -> Result<(), TIError> {
+        let icon = match icon {
+            Some(icon) => SniIcon::new(&icon)?,
+            None => SniIcon::default(),
+        };
+        self.tray.update(|tray| tray.overlay_icon = icon);
+
+        Ok(())
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        let icon = SniIcon::new(&icon)?;
+        self.tray.update(|tray| tray.attention_icon = icon);
 
         Ok(())
     }
+
+    pub fn set_attention_movie(&mut self, name: &str) -> Result<(), TIError> {
+        self.tray
+            .update(|tray| tray.attention_movie = name.to_string());
+
+        Ok(())
+    }
+
+    pub fn set_tooltip(&mut self, tooltip: Tooltip) -> Result<(), TIError> {
+        let icon = match &tooltip.icon {
+            Some(icon) => SniIcon::new(icon)?,
+            None => SniIcon::default(),
+        };
+        let tooltip = ToolTip {
+            title: tooltip.title,
+            description: tooltip.description,
+            icon_pixmap: icons(&icon.pixmaps),
+            icon_name: icon.name,
+        };
+        self.tray.update(|tray| tray.tooltip = tooltip);
+
+        Ok(())
+    }
+
+    pub(crate) fn enable_icon_action(&mut self, _action: IconAction) -> Result<(), TIError> {
+        // ksni forwards every icon action to `Tray`
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn set_icon_async(&mut self, icon: IconSource) -> Result<(), TIError> {
+        let icon = SniIcon::new(&icon)?;
+        self.animation = None;
+        self.update_async(move |tray| {
+            tray.animation = None;
+            tray.icon = icon;
+        })
+        .await
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn update_menu_async(&mut self) -> Result<(), TIError> {
+        self.update_async(|_| {}).await
+    }
+
+    #[cfg(feature = "async")]
+    async fn update_async<F>(&self, f: F) -> Result<(), TIError>
+    where
+        F: FnOnce(&mut Tray) + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.updates
+            .send((Box::new(f), tx))
+            .map_err(|_| TIError::new("The tray service has stopped"))?;
+
+        rx.await
+            .map_err(|_| TIError::new("The tray service has stopped"))
+    }
+}
+
+impl Drop for TrayItemLinux {
+    fn drop(&mut self) {
+        // The click thread holds a handle, which keeps the tray and with it
+        // the thread's senders alive
+        self.clicks.send(Click::Stop).ok();
+        self.tray.shutdown();
+    }
+}
+
+// Updates wait on the ksni model lock, which the service thread holds while it
+// answers D-Bus calls. The async methods hand them to this thread instead so
+// an executor thread is never blocked.
+#[cfg(feature = "async")]
+fn spawn_updater(handle: Handle<Tray>) -> mpsc::Sender<Update> {
+    let (tx, rx) = mpsc::channel::<Update>();
+    thread::spawn(move || {
+        for (update, done) in rx {
+            handle.update(update);
+            done.send(()).ok();
+        }
+    });
+
+    tx
 }
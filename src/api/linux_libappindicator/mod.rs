@@ -1,64 +1,291 @@
 use {
-    crate::{IconSource, TIError},
-    gtk::prelude::*,
+    crate::{
+        event::IconAction,
+        icon,
+        menu::{self, SharedMenu},
+        path_str, IconSource, MenuItem, MenuItemKind, PixelFormat, TIError, Tooltip, TrayStatus,
+    },
+    gtk::{
+        gdk_pixbuf::{Colorspace, Pixbuf},
+        prelude::*,
+    },
     libappindicator::{AppIndicator, AppIndicatorStatus},
+    std::{
+        env, fs,
+        path::{Path, PathBuf},
+        process,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    },
 };
 
+// Panels draw indicators at 16 to 32 pixels, larger pixmaps are scaled down
+const ICON_SIZE: i32 = 32;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
 pub struct TrayItemLinux {
     tray: AppIndicator,
     menu: gtk::Menu,
+    model: SharedMenu,
+    // libappindicator only loads icons by name or path, pixel icons are saved
+    // here as PNG files
+    icon_dir: PathBuf,
+    icon_file: Option<PathBuf>,
+    attention_file: Option<PathBuf>,
+    next_file: usize,
 }
 
 impl TrayItemLinux {
-    pub fn new(title: &str, icon: IconSource) -> Result<Self, TIError> {
+    pub fn new(title: &str, icon: IconSource, model: SharedMenu) -> Result<Self, TIError> {
+        // The gtk widgets panic when built anywhere else
+        if !gtk::is_initialized_main_thread() {
+            return Err(TIError::new(
+                "libappindicator needs GTK, call gtk::init() on this thread first",
+            ));
+        }
+
         let mut t = Self {
-            tray: AppIndicator::new(title, icon.as_str()),
+            tray: AppIndicator::new(title, ""),
             menu: gtk::Menu::new(),
+            model,
+            icon_dir: env::temp_dir().join(format!(
+                "tray-item-{}-{}",
+                process::id(),
+                NEXT_ID.fetch_add(1, Ordering::Relaxed)
+            )),
+            icon_file: None,
+            attention_file: None,
+            next_file: 0,
         };
 
         t.set_icon(icon)?;
+        t.set_status(TrayStatus::Active)?;
 
         Ok(t)
     }
 
     pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
-        self.tray.set_icon(icon.as_str());
-        self.tray.set_status(AppIndicatorStatus::Active);
+        let (name, file) = self.icon_name(&icon)?;
+        self.tray.set_icon(&name);
+        replace_file(&mut self.icon_file, file);
 
         Ok(())
     }
 
-    pub fn add_label(&mut self, label: &str) -> Result<(), TIError> {
-        let item = gtk::MenuItem::with_label(label);
-        item.set_sensitive(false);
-        self.menu.append(&item);
-        self.menu.show_all();
-        self.tray.set_menu(&mut self.menu);
+    pub fn set_title_label(&mut self, label: Option<&str>) -> Result<(), TIError> {
+        self.tray.set_label(label.unwrap_or(""), "");
+
+        Ok(())
+    }
+
+    pub fn set_icon_theme_path(&mut self, path: &Path) -> Result<(), TIError> {
+        self.tray.set_icon_theme_path(path_str(path)?);
 
         Ok(())
     }
 
-    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<(), TIError>
-    where
-        F: Fn() + Send + 'static,
-    {
-        let item = gtk::MenuItem::with_label(label);
-        item.connect_activate(move |_| {
-            cb();
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.tray.set_status(match status {
+            TrayStatus::Active => AppIndicatorStatus::Active,
+            TrayStatus::Passive => AppIndicatorStatus::Passive,
+            TrayStatus::NeedsAttention => AppIndicatorStatus::Attention,
         });
-        self.menu.append(&item);
-        self.menu.show_all();
-        self.tray.set_menu(&mut self.menu);
 
         Ok(())
     }
 
-    pub fn add_separator(&mut self) -> Result<(), TIError> {
-        let item = gtk::SeparatorMenuItem::new();
-        self.menu.append(&item);
-        self.menu.show_all();
+    pub fn set_overlay_icon(&mut self, _icon: Option<IconSource>) -> Result<(), TIError> {
+        Err(TIError::new(
+            "Overlay icons are not supported by libappindicator",
+        ))
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        let (name, file) = self.icon_name(&icon)?;
+        self.tray.set_attention_icon_full(&name, "");
+        replace_file(&mut self.attention_file, file);
+
+        Ok(())
+    }
+
+    pub fn set_animated_icon(
+        &mut self,
+        _frames: Vec<IconSource>,
+        _interval: Duration,
+    ) -> Result<(), TIError> {
+        Err(TIError::new(
+            "Animated icons are not supported by libappindicator",
+        ))
+    }
+
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        Ok(())
+    }
+
+    pub fn set_attention_movie(&mut self, _name: &str) -> Result<(), TIError> {
+        Err(TIError::new(
+            "Attention movies are not supported by libappindicator",
+        ))
+    }
+
+    pub fn set_tooltip(&mut self, tooltip: Tooltip) -> Result<(), TIError> {
+        // There is no tooltip in libappindicator, hosts show the title on hover
+        self.tray.set_title(&tooltip.title);
+
+        Ok(())
+    }
+
+    pub(crate) fn enable_icon_action(&mut self, action: IconAction) -> Result<(), TIError> {
+        Err(TIError::new(format!(
+            "{:?} is not supported, libappindicator always opens the menu",
+            action
+        )))
+    }
+
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
+        for child in self.menu.children() {
+            self.menu.remove(&child);
+        }
+
+        build_menu(&self.menu, self.model.lock().unwrap().items(), &self.model);
+
+        // Visibility is applied per item while building, show_all would override it
+        self.menu.show();
         self.tray.set_menu(&mut self.menu);
 
         Ok(())
     }
+
+    // The name to pass on for `icon`, along with the file pixels were saved to.
+    // Every file gets a new name, hosts cache icons by name.
+    fn icon_name(&mut self, icon: &IconSource) -> Result<(String, Option<PathBuf>), TIError> {
+        let (width, height, rgba) = match icon {
+            IconSource::Resource(name) => return Ok((name.to_string(), None)),
+            #[cfg(any(feature = "ksni", feature = "zbus", feature = "xembed"))]
+            IconSource::Data {
+                height,
+                width,
+                data,
+            } => (
+                *width,
+                *height,
+                icon::convert(
+                    *width,
+                    *height,
+                    PixelFormat::Argb32,
+                    PixelFormat::Rgba8,
+                    data,
+                )?,
+            ),
+            IconSource::Pixels {
+                width,
+                height,
+                format,
+                data,
+            } => (
+                *width,
+                *height,
+                icon::convert(*width, *height, *format, PixelFormat::Rgba8, data)?,
+            ),
+            IconSource::Pixmaps(pixmaps) => {
+                let pixmap = icon::closest(pixmaps, ICON_SIZE)
+                    .ok_or_else(|| TIError::new("The icon has no pixmaps"))?;
+                (
+                    pixmap.width,
+                    pixmap.height,
+                    pixmap.convert(PixelFormat::Rgba8)?,
+                )
+            }
+        };
+
+        let path = self.icon_dir.join(format!("{}.png", self.next_file));
+        self.next_file += 1;
+        fs::create_dir_all(&self.icon_dir)
+            .map_err(|e| TIError::new(format!("Error saving icon: {}", e)))?;
+        Pixbuf::from_mut_slice(rgba, Colorspace::Rgb, true, 8, width, height, width * 4)
+            .savev(&path, "png", &[])
+            .map_err(|e| TIError::new(format!("Error saving icon: {}", e)))?;
+
+        Ok((path_str(&path)?.to_string(), Some(path)))
+    }
+}
+
+impl Drop for TrayItemLinux {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.icon_dir).ok();
+    }
+}
+
+fn replace_file(slot: &mut Option<PathBuf>, file: Option<PathBuf>) {
+    if let Some(old) = std::mem::replace(slot, file) {
+        fs::remove_file(old).ok();
+    }
+}
+
+fn build_menu(menu: &gtk::Menu, items: &[MenuItem], model: &SharedMenu) {
+    for item in items {
+        match item.kind() {
+            MenuItemKind::Label => {
+                let label = gtk::MenuItem::with_label(item.label());
+                append(menu, &label, item);
+            }
+            MenuItemKind::Action => {
+                let menu_item = gtk::MenuItem::with_label(item.label());
+                let (model, id) = (SharedMenu::clone(model), item.id());
+                menu_item.connect_activate(move |_| {
+                    menu::click::activate(&model, id);
+                });
+                append(menu, &menu_item, item);
+            }
+            MenuItemKind::Check => {
+                let menu_item = gtk::CheckMenuItem::with_label(item.label());
+                // Set the state before connecting, set_active emits "activate" as well
+                menu_item.set_active(item.is_checked());
+                let (model, id) = (SharedMenu::clone(model), item.id());
+                menu_item.connect_activate(move |_| {
+                    menu::click::activate(&model, id);
+                });
+                append(menu, &menu_item, item);
+            }
+            MenuItemKind::RadioGroup => {
+                let mut group: Option<gtk::RadioMenuItem> = None;
+                for (index, option) in item.options().iter().enumerate() {
+                    let menu_item = match &group {
+                        Some(first) => {
+                            gtk::RadioMenuItem::with_label_from_widget(first, Some(option.as_str()))
+                        }
+                        None => gtk::RadioMenuItem::with_label(option),
+                    };
+                    menu_item.set_active(index == item.selected());
+                    // Both the old and the new option toggle, only report the new one
+                    let (model, id) = (SharedMenu::clone(model), item.id());
+                    menu_item.connect_toggled(move |menu_item| {
+                        if menu_item.is_active() {
+                            menu::click::select(&model, id, index);
+                        }
+                    });
+                    append(menu, &menu_item, item);
+                    group.get_or_insert(menu_item);
+                }
+            }
+            MenuItemKind::Separator => {
+                append(menu, &gtk::SeparatorMenuItem::new(), item);
+            }
+            MenuItemKind::Submenu => {
+                let submenu = gtk::Menu::new();
+                build_menu(&submenu, item.children(), model);
+
+                let menu_item = gtk::MenuItem::with_label(item.label());
+                menu_item.set_submenu(Some(&submenu));
+                append(menu, &menu_item, item);
+            }
+        }
+    }
+}
+
+fn append(menu: &gtk::Menu, widget: &impl IsA<gtk::MenuItem>, item: &MenuItem) {
+    widget.set_sensitive(item.is_enabled());
+    widget.set_visible(item.is_visible());
+    menu.append(widget);
 }
@@ -0,0 +1,226 @@
+// Docks a window into the `_NET_SYSTEM_TRAY_S<n>` tray of plain X11 desktops,
+// which have no StatusNotifierItem host
+
+mod popup;
+mod window;
+
+use {
+    crate::{
+        api::animation::{self, Animation, Ticker},
+        event::IconAction,
+        icon,
+        menu::SharedMenu,
+        IconSource, PixelFormat, Pixmap, TIError, Tooltip, TrayStatus,
+    },
+    std::{
+        fmt::Display,
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    },
+    window::{Command, X},
+};
+
+// Every size of an icon as RGBA8, the window picks the one closest to its own
+type TrayIcon = Vec<Pixmap>;
+
+// What the window shows, shared with the thread drawing it
+struct State {
+    icon: TrayIcon,
+    animation: Option<Animation<TrayIcon>>,
+    attention_icon: Option<TrayIcon>,
+    status: TrayStatus,
+}
+
+impl State {
+    fn current_icon(&self) -> &TrayIcon {
+        match (&self.attention_icon, &self.animation) {
+            (Some(icon), _) if self.status == TrayStatus::NeedsAttention => icon,
+            (_, Some(animation)) => animation.current(),
+            _ => &self.icon,
+        }
+    }
+}
+
+pub struct TrayItemLinux {
+    x: Arc<X>,
+    state: Arc<Mutex<State>>,
+    left_click_menu: Arc<AtomicBool>,
+    animation: Option<Ticker>,
+}
+
+impl TrayItemLinux {
+    pub fn new(title: &str, icon: IconSource, menu: SharedMenu) -> Result<Self, TIError> {
+        let state = Arc::new(Mutex::new(State {
+            icon: tray_icon(&icon)?,
+            animation: None,
+            attention_icon: None,
+            status: TrayStatus::Active,
+        }));
+        let x = Arc::new(X::connect(title)?);
+        let left_click_menu = Arc::new(AtomicBool::new(true));
+
+        let tray = window::Tray::new(
+            Arc::clone(&x),
+            title,
+            Arc::clone(&state),
+            menu,
+            Arc::clone(&left_click_menu),
+        );
+        thread::spawn(move || tray.run());
+
+        Ok(Self {
+            x,
+            state,
+            left_click_menu,
+            animation: None,
+        })
+    }
+
+    pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        let icon = tray_icon(&icon)?;
+        self.animation = None;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.animation = None;
+            state.icon = icon;
+        }
+
+        self.x.send(Command::Redraw)
+    }
+
+    pub fn set_animated_icon(
+        &mut self,
+        frames: Vec<IconSource>,
+        interval: Duration,
+    ) -> Result<(), TIError> {
+        let animation = Animation::new(
+            frames
+                .iter()
+                .map(tray_icon)
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        let frames = animation.frames();
+        self.state.lock().unwrap().animation = Some(animation);
+        self.x.send(Command::Redraw)?;
+
+        let (x, state) = (Arc::clone(&self.x), Arc::clone(&self.state));
+        self.animation = Some(Ticker::start(interval, move || {
+            if animation::advance(&mut state.lock().unwrap().animation, &frames) {
+                x.send(Command::Redraw).ok();
+            }
+        }));
+
+        Ok(())
+    }
+
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        self.animation = None;
+        self.state.lock().unwrap().animation = None;
+
+        self.x.send(Command::Redraw)
+    }
+
+    pub fn set_title_label(&mut self, _label: Option<&str>) -> Result<(), TIError> {
+        Err(TIError::new("Labels are not supported by XEmbed"))
+    }
+
+    pub fn set_icon_theme_path(&mut self, _path: &Path) -> Result<(), TIError> {
+        Err(TIError::new("Icon theme paths are not supported by XEmbed"))
+    }
+
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.state.lock().unwrap().status = status;
+
+        self.x.send(Command::Status)
+    }
+
+    pub fn set_overlay_icon(&mut self, _icon: Option<IconSource>) -> Result<(), TIError> {
+        Err(TIError::new("Overlay icons are not supported by XEmbed"))
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.state.lock().unwrap().attention_icon = Some(tray_icon(&icon)?);
+
+        self.x.send(Command::Redraw)
+    }
+
+    pub fn set_attention_movie(&mut self, _name: &str) -> Result<(), TIError> {
+        Err(TIError::new("Attention movies are not supported by XEmbed"))
+    }
+
+    pub fn set_tooltip(&mut self, tooltip: Tooltip) -> Result<(), TIError> {
+        // There is no tooltip in XEmbed, trays show the window name on hover
+        self.x.set_name(&tooltip.title)
+    }
+
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
+        // The menu is read from the model whenever it opens, this only closes
+        // one that is open so it can't show stale items
+        self.x.send(Command::Menu)
+    }
+
+    pub(crate) fn enable_icon_action(&mut self, action: IconAction) -> Result<(), TIError> {
+        if action == IconAction::Activate {
+            self.left_click_menu.store(false, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for TrayItemLinux {
+    fn drop(&mut self) {
+        // The window thread destroys the window and hangs up
+        self.x.send(Command::Quit).ok();
+    }
+}
+
+fn error(e: impl Display) -> TIError {
+    TIError::new(format!("X11 error: {}", e))
+}
+
+// XEmbed draws the pixels itself, there is no theme to look names up in
+fn tray_icon(icon: &IconSource) -> Result<TrayIcon, TIError> {
+    let rgba = |width, height, format, data| {
+        Ok(Pixmap {
+            width,
+            height,
+            format: PixelFormat::Rgba8,
+            data: icon::convert(width, height, format, PixelFormat::Rgba8, data)?,
+        })
+    };
+
+    match icon {
+        IconSource::Resource(name) => Err(TIError::new(format!(
+            "Named icons like {:?} are not supported by XEmbed, use pixels",
+            name
+        ))),
+        IconSource::Data {
+            height,
+            width,
+            data,
+        } => Ok(vec![rgba(*width, *height, PixelFormat::Argb32, data)?]),
+        IconSource::Pixels {
+            width,
+            height,
+            format,
+            data,
+        } => Ok(vec![rgba(*width, *height, *format, data)?]),
+        IconSource::Pixmaps(pixmaps) => pixmaps
+            .iter()
+            .map(|p| {
+                Ok(Pixmap {
+                    width: p.width,
+                    height: p.height,
+                    format: PixelFormat::Rgba8,
+                    data: p.convert(PixelFormat::Rgba8)?,
+                })
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,391 @@
+use {
+    super::window::{latin1, X},
+    crate::{MenuItem, MenuItemId, MenuItemKind},
+    x11rb::{
+        connection::Connection,
+        errors::ReplyOrIdError,
+        protocol::xproto::{
+            AtomEnum, ButtonPressEvent, Char2b, ConnectionExt as _, CreateWindowAux, EventMask,
+            GrabMode, PropMode, Rectangle, Segment, Window, WindowClass,
+        },
+        wrapper::ConnectionExt as _,
+        COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE,
+    },
+};
+
+// Space around the text of a row, and the height of a separator
+const PADDING: i16 = 3;
+const MARGIN: i16 = 8;
+const SEPARATOR: u16 = 7;
+
+// What a press on the menu asks for
+pub(super) enum Press {
+    Inert,
+    Outside,
+    Item(MenuItemId),
+    Option(MenuItemId, usize),
+}
+
+enum Action {
+    None,
+    Separator,
+    Item(MenuItemId),
+    Option(MenuItemId, usize),
+    Submenu(Vec<MenuItem>),
+}
+
+struct Row {
+    y: i16,
+    height: u16,
+    text: Vec<u8>,
+    enabled: bool,
+    action: Action,
+}
+
+// One open menu, submenus get a level of their own to the side
+struct Level {
+    window: Window,
+    x: i16,
+    y: i16,
+    width: u16,
+    rows: Vec<Row>,
+    hover: Option<usize>,
+}
+
+impl Level {
+    fn open(
+        x: &X,
+        title: &str,
+        items: &[MenuItem],
+        at: (i16, i16),
+        beside: Option<i16>,
+    ) -> Result<Self, ReplyOrIdError> {
+        let toggles = items
+            .iter()
+            .any(|item| matches!(item.kind(), MenuItemKind::Check | MenuItemKind::RadioGroup));
+        let text = |prefix: &str, label: &str| {
+            let prefix = if toggles { prefix } else { "" };
+            let mut text = latin1(&format!("{}{}", prefix, label));
+            // The most ImageText8 takes
+            text.truncate(255);
+            text
+        };
+        let line = (x.ascent + x.descent + 2 * PADDING) as u16;
+
+        let mut rows = vec![];
+        let mut y = 0;
+        let mut push = |height, text, enabled, action| {
+            rows.push(Row {
+                y,
+                height,
+                text,
+                enabled,
+                action,
+            });
+            y += height as i16;
+        };
+        for item in items.iter().filter(|item| item.is_visible()) {
+            let enabled = item.is_enabled();
+            match item.kind() {
+                MenuItemKind::Label => push(line, text("    ", item.label()), false, Action::None),
+                MenuItemKind::Action => push(
+                    line,
+                    text("    ", item.label()),
+                    enabled,
+                    Action::Item(item.id()),
+                ),
+                MenuItemKind::Check => {
+                    let mark = if item.is_checked() { "[x] " } else { "[ ] " };
+                    push(
+                        line,
+                        text(mark, item.label()),
+                        enabled,
+                        Action::Item(item.id()),
+                    )
+                }
+                MenuItemKind::RadioGroup => {
+                    for (index, option) in item.options().iter().enumerate() {
+                        let mark = if index == item.selected() {
+                            "(*) "
+                        } else {
+                            "( ) "
+                        };
+                        push(
+                            line,
+                            text(mark, option),
+                            enabled,
+                            Action::Option(item.id(), index),
+                        );
+                    }
+                }
+                MenuItemKind::Separator => push(SEPARATOR, vec![], false, Action::Separator),
+                MenuItemKind::Submenu => push(
+                    line,
+                    text("    ", item.label()),
+                    enabled,
+                    Action::Submenu(item.children().to_vec()),
+                ),
+            }
+        }
+        // An empty menu still needs a window to take the click that closes it
+        let height = (y as u16).max(line);
+
+        let mut width = 0;
+        for row in &rows {
+            let string: Vec<Char2b> = row
+                .text
+                .iter()
+                .map(|&byte2| Char2b { byte1: 0, byte2 })
+                .collect();
+            let extents = x.conn.query_text_extents(x.gc, &string)?.reply()?;
+            width = width.max(extents.overall_width);
+        }
+        // Room for the arrow of submenus
+        let width = (width as i16 + 4 * MARGIN) as u16;
+
+        // Keep it on screen, submenus flip to the other side of their parent
+        let (screen_width, screen_height) = (
+            x.screen.width_in_pixels as i16,
+            x.screen.height_in_pixels as i16,
+        );
+        let (mut left, mut top) = at;
+        if left + width as i16 > screen_width {
+            left = match beside {
+                Some(parent) => parent - width as i16,
+                None => screen_width - width as i16,
+            };
+        }
+        if top + height as i16 > screen_height {
+            top = match beside {
+                Some(_) => screen_height - height as i16,
+                None => top - height as i16,
+            };
+        }
+        let (left, top) = (left.max(0), top.max(0));
+
+        let window = x.conn.generate_id()?;
+        x.conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            window,
+            x.screen.root,
+            left,
+            top,
+            width,
+            height,
+            1,
+            WindowClass::INPUT_OUTPUT,
+            COPY_FROM_PARENT,
+            &CreateWindowAux::new()
+                .background_pixel(x.colors.background)
+                .border_pixel(x.colors.foreground)
+                .override_redirect(1)
+                .event_mask(
+                    EventMask::EXPOSURE | EventMask::BUTTON_PRESS | EventMask::POINTER_MOTION,
+                ),
+        )?;
+        x.name(window, title)?;
+        x.conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            x.atoms._NET_WM_WINDOW_TYPE,
+            AtomEnum::ATOM,
+            &[x.atoms._NET_WM_WINDOW_TYPE_POPUP_MENU],
+        )?;
+        x.conn.map_window(window)?;
+
+        Ok(Self {
+            window,
+            x: left,
+            y: top,
+            width,
+            rows,
+            hover: None,
+        })
+    }
+
+    fn row_at(&self, x: i16, y: i16) -> Option<usize> {
+        if x < 0 || x >= self.width as i16 {
+            return None;
+        }
+
+        self.rows
+            .iter()
+            .position(|row| y >= row.y && y < row.y + row.height as i16)
+    }
+
+    fn draw(&self, x: &X) -> Result<(), ReplyOrIdError> {
+        let colors = &x.colors;
+        for (index, row) in self.rows.iter().enumerate() {
+            let highlighted = self.hover == Some(index) && row.enabled;
+            let (foreground, background) = match (highlighted, row.enabled) {
+                (true, _) => (colors.background, colors.highlight),
+                (false, true) => (colors.foreground, colors.background),
+                (false, false) => (colors.disabled, colors.background),
+            };
+
+            x.set_colors(background, background)?;
+            x.conn.poly_fill_rectangle(
+                self.window,
+                x.gc,
+                &[Rectangle {
+                    x: 0,
+                    y: row.y,
+                    width: self.width,
+                    height: row.height,
+                }],
+            )?;
+
+            x.set_colors(foreground, background)?;
+            let baseline = row.y + PADDING + x.ascent;
+            match row.action {
+                Action::Separator => {
+                    let middle = row.y + row.height as i16 / 2;
+                    x.set_colors(colors.disabled, background)?;
+                    x.conn.poly_segment(
+                        self.window,
+                        x.gc,
+                        &[Segment {
+                            x1: MARGIN,
+                            y1: middle,
+                            x2: self.width as i16 - MARGIN,
+                            y2: middle,
+                        }],
+                    )?;
+                }
+                Action::Submenu(_) => {
+                    x.conn
+                        .image_text8(self.window, x.gc, MARGIN, baseline, &row.text)?;
+                    x.conn.image_text8(
+                        self.window,
+                        x.gc,
+                        self.width as i16 - 2 * MARGIN,
+                        baseline,
+                        b">",
+                    )?;
+                }
+                _ => {
+                    x.conn
+                        .image_text8(self.window, x.gc, MARGIN, baseline, &row.text)?;
+                }
+            }
+        }
+        x.set_colors(colors.foreground, colors.background)?;
+        x.conn.flush()?;
+
+        Ok(())
+    }
+}
+
+// The menu while it is open, it holds the pointer so a click anywhere else
+// closes it
+pub(super) struct Popup {
+    title: String,
+    levels: Vec<Level>,
+}
+
+impl Popup {
+    pub(super) fn open(
+        x: &X,
+        title: &str,
+        items: &[MenuItem],
+        at: (i16, i16),
+    ) -> Result<Self, ReplyOrIdError> {
+        let level = Level::open(x, title, items, at, None)?;
+        // Reported to the menu windows while over them, to the first one
+        // otherwise
+        x.conn.grab_pointer(
+            true,
+            level.window,
+            EventMask::BUTTON_PRESS | EventMask::POINTER_MOTION,
+            GrabMode::ASYNC,
+            GrabMode::ASYNC,
+            NONE,
+            NONE,
+            CURRENT_TIME,
+        )?;
+        x.conn.flush()?;
+
+        Ok(Self {
+            title: title.to_string(),
+            levels: vec![level],
+        })
+    }
+
+    pub(super) fn close(self, x: &X) -> Result<(), ReplyOrIdError> {
+        x.conn.ungrab_pointer(CURRENT_TIME)?;
+        for level in self.levels {
+            x.conn.destroy_window(level.window)?;
+        }
+        x.conn.flush()?;
+
+        Ok(())
+    }
+
+    pub(super) fn draw(&self, x: &X, window: Window) -> Result<(), ReplyOrIdError> {
+        match self.levels.iter().find(|level| level.window == window) {
+            Some(level) => level.draw(x),
+            None => Ok(()),
+        }
+    }
+
+    pub(super) fn press(&mut self, x: &X, e: &ButtonPressEvent) -> Result<Press, ReplyOrIdError> {
+        let Some(depth) = self.levels.iter().position(|level| level.window == e.event) else {
+            return Ok(Press::Outside);
+        };
+        let Some(index) = self.levels[depth].row_at(e.event_x, e.event_y) else {
+            return Ok(Press::Outside);
+        };
+
+        let row = &self.levels[depth].rows[index];
+        Ok(match row.action {
+            _ if !row.enabled => Press::Inert,
+            Action::Item(id) => Press::Item(id),
+            Action::Option(id, index) => Press::Option(id, index),
+            // Without a pointer that moved there first, clicking opens it
+            Action::Submenu(_) => {
+                self.hover(x, depth, Some(index))?;
+                Press::Inert
+            }
+            Action::None | Action::Separator => Press::Inert,
+        })
+    }
+
+    pub(super) fn motion(
+        &mut self,
+        x: &X,
+        window: Window,
+        event_x: i16,
+        event_y: i16,
+    ) -> Result<(), ReplyOrIdError> {
+        if let Some(depth) = self.levels.iter().position(|level| level.window == window) {
+            let index = self.levels[depth].row_at(event_x, event_y);
+            if index != self.levels[depth].hover {
+                self.hover(x, depth, index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Highlights a row, opening its submenu and closing any deeper one
+    fn hover(&mut self, x: &X, depth: usize, index: Option<usize>) -> Result<(), ReplyOrIdError> {
+        for level in self.levels.drain(depth + 1..) {
+            x.conn.destroy_window(level.window)?;
+        }
+
+        let level = &mut self.levels[depth];
+        level.hover = index;
+        level.draw(x)?;
+
+        if let Some(row) = index.map(|index| &level.rows[index]) {
+            if let (Action::Submenu(items), true) = (&row.action, row.enabled) {
+                let at = (level.x + level.width as i16, level.y + row.y);
+                let submenu = Level::open(x, &self.title, items, at, Some(level.x))?;
+                self.levels.push(submenu);
+            }
+        }
+        x.conn.flush()?;
+
+        Ok(())
+    }
+}
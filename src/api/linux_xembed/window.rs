@@ -0,0 +1,519 @@
+use {
+    super::{error, popup::Popup, popup::Press, State},
+    crate::{
+        event::SharedEvents,
+        icon,
+        menu::{self, SharedMenu},
+        Pixmap, ScrollOrientation, TIError, TrayEvent, TrayStatus,
+    },
+    std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    x11rb::{
+        connection::Connection,
+        errors::ReplyOrIdError,
+        protocol::{
+            xproto::{
+                AtomEnum, BackPixmap, ButtonPressEvent, ChangeGCAux, ChangeWindowAttributesAux,
+                ClientMessageEvent, ConnectionExt as _, CreateGCAux, CreateWindowAux, EventMask,
+                Gcontext, ImageFormat, ImageOrder, PropMode, Screen, Window, WindowClass,
+            },
+            Event,
+        },
+        rust_connection::RustConnection,
+        wrapper::ConnectionExt as _,
+        COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE,
+    },
+};
+
+x11rb::atom_manager! {
+    pub(super) Atoms: AtomsCookie {
+        MANAGER,
+        UTF8_STRING,
+        _NET_WM_NAME,
+        _NET_WM_WINDOW_TYPE,
+        _NET_WM_WINDOW_TYPE_POPUP_MENU,
+        _NET_SYSTEM_TRAY_OPCODE,
+        _XEMBED_INFO,
+        _TRAY_ITEM_COMMAND,
+    }
+}
+
+// Trays resize the window, this is only what it starts out with
+const SIZE: u16 = 22;
+
+const SYSTEM_TRAY_REQUEST_DOCK: u32 = 0;
+const XEMBED_MAPPED: u32 = 1;
+
+// Sent from `TrayItemLinux` to its own window, so everything touching the
+// window happens on the thread reading its events
+#[derive(Clone, Copy)]
+pub(super) enum Command {
+    Redraw,
+    Status,
+    Menu,
+    Quit,
+}
+
+impl Command {
+    const ALL: [Command; 4] = [
+        Command::Redraw,
+        Command::Status,
+        Command::Menu,
+        Command::Quit,
+    ];
+}
+
+pub(super) struct Colors {
+    pub(super) foreground: u32,
+    pub(super) background: u32,
+    pub(super) disabled: u32,
+    pub(super) highlight: u32,
+}
+
+// The connection and what every request needs, shared by both threads
+pub(super) struct X {
+    pub(super) conn: RustConnection,
+    pub(super) atoms: Atoms,
+    pub(super) screen: Screen,
+    pub(super) window: Window,
+    pub(super) gc: Gcontext,
+    pub(super) colors: Colors,
+    pub(super) ascent: i16,
+    pub(super) descent: i16,
+    selection: u32,
+}
+
+impl X {
+    pub(super) fn connect(title: &str) -> Result<Self, TIError> {
+        let (conn, screen) = x11rb::connect(None).map_err(error)?;
+        Self::setup(conn, screen, title).map_err(error)
+    }
+
+    fn setup(conn: RustConnection, screen: usize, title: &str) -> Result<Self, ReplyOrIdError> {
+        let atoms = Atoms::new(&conn)?.reply()?;
+        let selection = conn
+            .intern_atom(false, format!("_NET_SYSTEM_TRAY_S{}", screen).as_bytes())?
+            .reply()?
+            .atom;
+        let screen = conn.setup().roots[screen].clone();
+
+        let color = |red, green, blue| -> Result<u32, ReplyOrIdError> {
+            let reply = conn.alloc_color(screen.default_colormap, red, green, blue)?;
+            Ok(reply.reply()?.pixel)
+        };
+        let colors = Colors {
+            foreground: screen.black_pixel,
+            background: screen.white_pixel,
+            disabled: color(0x8888, 0x8888, 0x8888)?,
+            highlight: color(0x3434, 0x6565, 0xa4a4)?,
+        };
+
+        // Trays paint their own background, the icon is drawn over it
+        let window = conn.generate_id()?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            window,
+            screen.root,
+            0,
+            0,
+            SIZE,
+            SIZE,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            COPY_FROM_PARENT,
+            &CreateWindowAux::new()
+                .background_pixmap(BackPixmap::PARENT_RELATIVE)
+                .event_mask(
+                    EventMask::EXPOSURE | EventMask::STRUCTURE_NOTIFY | EventMask::BUTTON_PRESS,
+                ),
+        )?;
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            atoms._XEMBED_INFO,
+            atoms._XEMBED_INFO,
+            &[0, XEMBED_MAPPED],
+        )?;
+
+        // Trays announce themselves on the root window
+        conn.change_window_attributes(
+            screen.root,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY),
+        )?;
+
+        let font = conn.generate_id()?;
+        conn.open_font(font, b"fixed")?;
+        let metrics = conn.query_font(font)?.reply()?;
+        let gc = conn.generate_id()?;
+        conn.create_gc(
+            gc,
+            window,
+            &CreateGCAux::new()
+                .font(font)
+                .foreground(colors.foreground)
+                .background(colors.background),
+        )?;
+
+        let x = Self {
+            conn,
+            atoms,
+            screen,
+            window,
+            gc,
+            colors,
+            ascent: metrics.font_ascent,
+            descent: metrics.font_descent,
+            selection,
+        };
+        x.name(window, title)?;
+
+        Ok(x)
+    }
+
+    pub(super) fn send(&self, command: Command) -> Result<(), TIError> {
+        let message = ClientMessageEvent::new(
+            32,
+            self.window,
+            self.atoms._TRAY_ITEM_COMMAND,
+            [command as u32, 0, 0, 0, 0],
+        );
+        // Without an event mask it goes to the client that created the window
+        self.conn
+            .send_event(false, self.window, EventMask::NO_EVENT, message)
+            .map_err(error)?;
+        self.conn.flush().map_err(error)
+    }
+
+    pub(super) fn set_name(&self, name: &str) -> Result<(), TIError> {
+        self.name(self.window, name).map_err(error)?;
+        self.conn.flush().map_err(error)
+    }
+
+    pub(super) fn name(&self, window: Window, name: &str) -> Result<(), ReplyOrIdError> {
+        self.conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_NAME,
+            AtomEnum::STRING,
+            &latin1(name),
+        )?;
+        self.conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            self.atoms._NET_WM_NAME,
+            self.atoms.UTF8_STRING,
+            name.as_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    pub(super) fn set_colors(
+        &self,
+        foreground: u32,
+        background: u32,
+    ) -> Result<(), ReplyOrIdError> {
+        self.conn.change_gc(
+            self.gc,
+            &ChangeGCAux::new()
+                .foreground(foreground)
+                .background(background),
+        )?;
+
+        Ok(())
+    }
+}
+
+// Core fonts only know Latin-1
+pub(super) fn latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| u8::try_from(u32::from(c)).unwrap_or(b'?'))
+        .collect()
+}
+
+// Owns the window, runs on its own thread until `Command::Quit`
+pub(super) struct Tray {
+    x: Arc<X>,
+    title: String,
+    state: Arc<Mutex<State>>,
+    menu: SharedMenu,
+    events: SharedEvents,
+    left_click_menu: Arc<AtomicBool>,
+    manager: Window,
+    size: (u16, u16),
+    popup: Option<Popup>,
+}
+
+impl Tray {
+    pub(super) fn new(
+        x: Arc<X>,
+        title: &str,
+        state: Arc<Mutex<State>>,
+        menu: SharedMenu,
+        left_click_menu: Arc<AtomicBool>,
+    ) -> Self {
+        let events = SharedEvents::clone(menu.lock().unwrap().events());
+
+        Self {
+            x,
+            title: title.to_string(),
+            state,
+            menu,
+            events,
+            left_click_menu,
+            manager: NONE,
+            size: (SIZE, SIZE),
+            popup: None,
+        }
+    }
+
+    pub(super) fn run(mut self) {
+        self.dock().ok();
+
+        while let Ok(event) = self.x.conn.wait_for_event() {
+            match self.handle(event) {
+                Ok(true) => (),
+                // Errors about single requests, like drawing into a window
+                // that isn't mapped yet, are expected
+                Err(ReplyOrIdError::X11Error(_)) => (),
+                Ok(false) | Err(_) => break,
+            }
+        }
+    }
+
+    fn handle(&mut self, event: Event) -> Result<bool, ReplyOrIdError> {
+        let window = self.x.window;
+
+        match event {
+            Event::ClientMessage(e) if e.type_ == self.x.atoms._TRAY_ITEM_COMMAND => {
+                let command = Command::ALL.get(e.data.as_data32()[0] as usize);
+                match command {
+                    Some(Command::Redraw) => self.draw()?,
+                    Some(Command::Status) => self.show()?,
+                    Some(Command::Menu) => self.close_popup()?,
+                    Some(Command::Quit) => {
+                        self.close_popup()?;
+                        self.x.conn.destroy_window(window)?;
+                        self.x.conn.flush()?;
+                        return Ok(false);
+                    }
+                    None => (),
+                }
+            }
+            // A tray started, or replaced the one we were docked in
+            Event::ClientMessage(e)
+                if e.type_ == self.x.atoms.MANAGER && e.data.as_data32()[1] == self.x.selection =>
+            {
+                self.dock()?;
+            }
+            Event::DestroyNotify(e) if e.window == self.manager => self.manager = NONE,
+            // The tray went away and handed the window back, don't let it
+            // show up as a window of its own
+            Event::ReparentNotify(e) if e.window == window && e.parent == self.x.screen.root => {
+                self.x.conn.unmap_window(window)?;
+                self.x.conn.flush()?;
+            }
+            Event::ConfigureNotify(e) if e.window == window && self.size != (e.width, e.height) => {
+                self.size = (e.width, e.height);
+                self.draw()?;
+            }
+            Event::Expose(e) if e.count == 0 => {
+                if e.window == window {
+                    self.draw()?;
+                } else if let Some(popup) = &self.popup {
+                    popup.draw(&self.x, e.window)?;
+                }
+            }
+            Event::ButtonPress(e) => match &mut self.popup {
+                // Clicks anywhere but the menu close it
+                Some(popup) => match popup.press(&self.x, &e)? {
+                    Press::Inert => (),
+                    Press::Outside => self.close_popup()?,
+                    Press::Item(id) => {
+                        self.close_popup()?;
+                        menu::click::activate(&self.menu, id);
+                    }
+                    Press::Option(id, index) => {
+                        self.close_popup()?;
+                        menu::click::select(&self.menu, id, index);
+                    }
+                },
+                None if e.event == window => self.click(&e)?,
+                None => (),
+            },
+            Event::MotionNotify(e) => {
+                if let Some(popup) = &mut self.popup {
+                    popup.motion(&self.x, e.event, e.event_x, e.event_y)?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(true)
+    }
+
+    // Asks the current tray, if there is one, to embed the window
+    fn dock(&mut self) -> Result<(), ReplyOrIdError> {
+        let conn = &self.x.conn;
+        let manager = conn.get_selection_owner(self.x.selection)?.reply()?.owner;
+        if manager == NONE {
+            // Docks once a tray announces itself with MANAGER
+            return Ok(());
+        }
+
+        // To notice when it goes away
+        conn.change_window_attributes(
+            manager,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::STRUCTURE_NOTIFY),
+        )?;
+        let message = ClientMessageEvent::new(
+            32,
+            manager,
+            self.x.atoms._NET_SYSTEM_TRAY_OPCODE,
+            [CURRENT_TIME, SYSTEM_TRAY_REQUEST_DOCK, self.x.window, 0, 0],
+        );
+        conn.send_event(false, manager, EventMask::NO_EVENT, message)?;
+        conn.flush()?;
+        self.manager = manager;
+
+        Ok(())
+    }
+
+    // Passive items ask the tray to hide them through the XEmbed flags
+    fn show(&mut self) -> Result<(), ReplyOrIdError> {
+        let visible = self.state.lock().unwrap().status != TrayStatus::Passive;
+        let conn = &self.x.conn;
+        conn.change_property32(
+            PropMode::REPLACE,
+            self.x.window,
+            self.x.atoms._XEMBED_INFO,
+            self.x.atoms._XEMBED_INFO,
+            &[0, if visible { XEMBED_MAPPED } else { 0 }],
+        )?;
+        if self.manager != NONE {
+            if visible {
+                conn.map_window(self.x.window)?;
+            } else {
+                conn.unmap_window(self.x.window)?;
+            }
+        }
+
+        self.draw()
+    }
+
+    fn click(&mut self, e: &ButtonPressEvent) -> Result<(), ReplyOrIdError> {
+        let (x, y) = (i32::from(e.root_x), i32::from(e.root_y));
+        let scroll = |delta, orientation| TrayEvent::Scrolled { delta, orientation };
+        let event = match e.detail {
+            1 if !self.left_click_menu.load(Ordering::Relaxed) => TrayEvent::IconActivated { x, y },
+            1 | 3 => {
+                let items = self.menu.lock().unwrap().items().to_vec();
+                self.popup = Some(Popup::open(
+                    &self.x,
+                    &self.title,
+                    &items,
+                    (e.root_x, e.root_y),
+                )?);
+                return Ok(());
+            }
+            2 => TrayEvent::SecondaryActivated { x, y },
+            4 => scroll(1, ScrollOrientation::Vertical),
+            5 => scroll(-1, ScrollOrientation::Vertical),
+            6 => scroll(1, ScrollOrientation::Horizontal),
+            7 => scroll(-1, ScrollOrientation::Horizontal),
+            _ => return Ok(()),
+        };
+        self.events.send(event);
+
+        Ok(())
+    }
+
+    fn close_popup(&mut self) -> Result<(), ReplyOrIdError> {
+        match self.popup.take() {
+            Some(popup) => popup.close(&self.x),
+            None => Ok(()),
+        }
+    }
+
+    // Blends the icon over what the tray painted behind the window
+    fn draw(&self) -> Result<(), ReplyOrIdError> {
+        let (x, window) = (&self.x, self.x.window);
+        let (width, height) = self.size;
+        x.conn.clear_area(false, window, 0, 0, 0, 0)?;
+
+        let side = width.min(height);
+        let pixmap = {
+            let state = self.state.lock().unwrap();
+            icon::closest(state.current_icon(), i32::from(side)).cloned()
+        };
+        let Some(pixmap) = pixmap else {
+            x.conn.flush()?;
+            return Ok(());
+        };
+
+        let image = x
+            .conn
+            .get_image(ImageFormat::Z_PIXMAP, window, 0, 0, width, height, !0)?
+            .reply()?;
+        let mut data = image.data;
+        // Only 32 bits per pixel are handled, what every TrueColor visual uses
+        if data.len() != usize::from(width) * usize::from(height) * 4 {
+            return Ok(());
+        }
+        let lsb_first = x.conn.setup().image_byte_order == ImageOrder::LSB_FIRST;
+        blend(&mut data, (width, height), &pixmap, lsb_first);
+
+        x.conn.put_image(
+            ImageFormat::Z_PIXMAP,
+            window,
+            x.gc,
+            width,
+            height,
+            0,
+            0,
+            0,
+            image.depth,
+            &data,
+        )?;
+        x.conn.flush()?;
+
+        Ok(())
+    }
+}
+
+// Scales the RGBA8 `pixmap` to fit the middle of `data`, a 32 bit ZPixmap of
+// `size`, keeping its aspect ratio
+fn blend(data: &mut [u8], size: (u16, u16), pixmap: &Pixmap, lsb_first: bool) {
+    let (width, height) = (usize::from(size.0), usize::from(size.1));
+    let (source_width, source_height) = (pixmap.width as usize, pixmap.height as usize);
+    let longest = source_width.max(source_height);
+    let side = width.min(height);
+    if longest == 0 || side == 0 {
+        return;
+    }
+
+    let (scaled_width, scaled_height) = (
+        source_width * side / longest,
+        source_height * side / longest,
+    );
+    let (left, top) = ((width - scaled_width) / 2, (height - scaled_height) / 2);
+    // Offsets of red, green and blue in a pixel
+    let channels = if lsb_first { [2, 1, 0] } else { [1, 2, 3] };
+
+    for y in 0..scaled_height {
+        for x in 0..scaled_width {
+            let source = ((y * source_height / scaled_height) * source_width
+                + x * source_width / scaled_width)
+                * 4;
+            let target = ((top + y) * width + left + x) * 4;
+            let alpha = u32::from(pixmap.data[source + 3]);
+            for (channel, offset) in channels.into_iter().enumerate() {
+                let color = u32::from(pixmap.data[source + channel]);
+                let behind = u32::from(data[target + offset]);
+                data[target + offset] = ((color * alpha + behind * (255 - alpha)) / 255) as u8;
+            }
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use {
+    crate::api::sni::{Click, Clicks},
+    crate::{MenuItem, MenuItemId, MenuItemKind},
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    },
+    zbus::{
+        fdo, interface,
+        object_server::SignalEmitter,
+        zvariant::{OwnedValue, StructureBuilder, Value},
+    },
+};
+
+pub(super) const PATH: &str = "/MenuBar";
+
+type Properties = HashMap<String, OwnedValue>;
+
+// `(id, properties, children)`, children are variants holding the same struct
+type Node = (i32, Properties, Vec<OwnedValue>);
+
+// Ids of the model's items are derived from their `MenuItemId`, radio options
+// have no id of their own and are numbered from here on instead
+const FIRST_OPTION_ID: i32 = 1 << 30;
+
+// What a dbusmenu id stands for in the model
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    Root,
+    Item(MenuItemId),
+    Option(MenuItemId, usize),
+}
+
+#[derive(Clone, PartialEq)]
+struct Entry {
+    parent: i32,
+    children: Vec<i32>,
+    target: Target,
+    separator: bool,
+    submenu: bool,
+    toggle_type: Option<&'static str>,
+    toggle_state: i32,
+    label: String,
+    enabled: bool,
+    visible: bool,
+}
+
+impl Entry {
+    fn new(parent: i32, target: Target, item: &MenuItem) -> Self {
+        Self {
+            parent,
+            children: vec![],
+            target,
+            separator: item.kind() == MenuItemKind::Separator,
+            submenu: item.kind() == MenuItemKind::Submenu,
+            toggle_type: None,
+            toggle_state: 0,
+            label: item.label().to_string(),
+            enabled: item.is_enabled(),
+            visible: item.is_visible(),
+        }
+    }
+
+    // Everything but the properties hosts can update in place
+    fn same_shape(&self, other: &Entry) -> bool {
+        self.parent == other.parent
+            && self.children == other.children
+            && self.target == other.target
+            && self.separator == other.separator
+            && self.submenu == other.submenu
+            && self.toggle_type == other.toggle_type
+    }
+
+    // All properties when `names` is empty, like the spec asks
+    fn properties(&self, names: &[String]) -> Properties {
+        let mut properties = Properties::new();
+        let mut add = |name: &str, value: Value| {
+            if names.is_empty() || names.iter().any(|n| n == name) {
+                properties.insert(name.to_string(), value.try_into().unwrap());
+            }
+        };
+
+        if self.separator {
+            add("type", "separator".into());
+        } else {
+            add("label", self.label.as_str().into());
+        }
+        add("enabled", self.enabled.into());
+        add("visible", self.visible.into());
+        if let Some(toggle_type) = self.toggle_type {
+            add("toggle-type", toggle_type.into());
+            add("toggle-state", self.toggle_state.into());
+        }
+        if self.submenu {
+            add("children-display", "submenu".into());
+        }
+
+        properties
+    }
+}
+
+/// What hosts have to be told after the menu changed.
+#[derive(Default)]
+pub(super) struct Change {
+    pub(super) properties: Vec<(i32, Properties)>,
+    // New revision and the entry whose children have to be fetched again
+    pub(super) layout: Option<(u32, i32)>,
+}
+
+// The menu flattened the way dbusmenu sees it. Ids stay the same for as long
+// as the items they stand for exist, so hosts only refetch what changed.
+pub(super) struct Layout {
+    revision: u32,
+    entries: HashMap<i32, Entry>,
+    options: HashMap<(MenuItemId, usize), i32>,
+    next_option: i32,
+}
+
+impl Layout {
+    pub(super) fn new(items: &[MenuItem]) -> Self {
+        let mut layout = Self {
+            revision: 0,
+            entries: HashMap::new(),
+            options: HashMap::new(),
+            next_option: FIRST_OPTION_ID,
+        };
+        layout.entries = layout.flatten(items);
+
+        layout
+    }
+
+    pub(super) fn update(&mut self, items: &[MenuItem]) -> Change {
+        let entries = self.flatten(items);
+        let mut change = Change::default();
+        // Added and removed entries show up in the children of their parent
+        let mut reshaped = vec![];
+        for (&id, new) in &entries {
+            match self.entries.get(&id) {
+                Some(old) if !new.same_shape(old) => reshaped.push(id),
+                Some(old) if new != old => change.properties.push((id, new.properties(&[]))),
+                _ => (),
+            }
+        }
+        if !reshaped.is_empty() {
+            self.revision += 1;
+            change.layout = Some((self.revision, common_parent(&entries, &reshaped)));
+        }
+        self.entries = entries;
+
+        change
+    }
+
+    fn entry(&self, id: i32) -> fdo::Result<&Entry> {
+        self.entries
+            .get(&id)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No menu item with id {}", id)))
+    }
+
+    fn node(&self, id: i32, depth: i32, names: &[String]) -> Node {
+        let entry = &self.entries[&id];
+        let children = match depth {
+            0 => vec![],
+            _ => entry
+                .children
+                .iter()
+                .map(|&child| {
+                    let (id, properties, children) = self.node(child, depth - 1, names);
+                    let node = StructureBuilder::new()
+                        .add_field(id)
+                        .add_field(properties)
+                        .add_field(children)
+                        .build()
+                        .unwrap();
+                    Value::from(node).try_into().unwrap()
+                })
+                .collect(),
+        };
+
+        (id, entry.properties(names), children)
+    }
+
+    fn flatten(&mut self, items: &[MenuItem]) -> HashMap<i32, Entry> {
+        let mut entries = HashMap::new();
+        entries.insert(
+            0,
+            Entry {
+                parent: 0,
+                children: vec![],
+                target: Target::Root,
+                separator: false,
+                submenu: true,
+                toggle_type: None,
+                toggle_state: 0,
+                label: String::new(),
+                enabled: true,
+                visible: true,
+            },
+        );
+        // Options that are gone give up their ids
+        let mut options = HashMap::new();
+        self.add(&mut entries, &mut options, 0, items);
+        self.options = options;
+
+        entries
+    }
+
+    fn add(
+        &mut self,
+        entries: &mut HashMap<i32, Entry>,
+        options: &mut HashMap<(MenuItemId, usize), i32>,
+        parent: i32,
+        items: &[MenuItem],
+    ) {
+        let push = |entries: &mut HashMap<i32, Entry>, id, entry| {
+            entries.insert(id, entry);
+            entries.get_mut(&parent).unwrap().children.push(id);
+        };
+
+        for item in items {
+            let id = item_id(item.id());
+            match item.kind() {
+                // dbusmenu has no groups, every option is an item of its own
+                MenuItemKind::RadioGroup => {
+                    for (index, option) in item.options().iter().enumerate() {
+                        let key = (item.id(), index);
+                        let id = match self.options.get(&key) {
+                            Some(&id) => id,
+                            None => {
+                                self.next_option += 1;
+                                self.next_option - 1
+                            }
+                        };
+                        options.insert(key, id);
+
+                        let mut entry = Entry::new(parent, Target::Option(item.id(), index), item);
+                        entry.label = option.clone();
+                        entry.toggle_type = Some("radio");
+                        entry.toggle_state = (index == item.selected()) as i32;
+                        push(entries, id, entry);
+                    }
+                }
+                MenuItemKind::Check => {
+                    let mut entry = Entry::new(parent, Target::Item(item.id()), item);
+                    entry.toggle_type = Some("checkmark");
+                    entry.toggle_state = item.is_checked() as i32;
+                    push(entries, id, entry);
+                }
+                MenuItemKind::Submenu => {
+                    push(
+                        entries,
+                        id,
+                        Entry::new(parent, Target::Item(item.id()), item),
+                    );
+                    self.add(entries, options, id, item.children());
+                }
+                _ => push(
+                    entries,
+                    id,
+                    Entry::new(parent, Target::Item(item.id()), item),
+                ),
+            }
+        }
+    }
+}
+
+// 0 is the root, model ids start at 0 too
+fn item_id(id: MenuItemId) -> i32 {
+    id.index() as i32 + 1
+}
+
+// The deepest entry all of `ids` are in, itself for a single one
+fn common_parent(entries: &HashMap<i32, Entry>, ids: &[i32]) -> i32 {
+    let ancestors = |mut id: i32| {
+        let mut chain = vec![id];
+        while id != 0 {
+            id = entries[&id].parent;
+            chain.push(id);
+        }
+        chain
+    };
+
+    let mut common = ancestors(ids[0]);
+    for &id in &ids[1..] {
+        let chain = ancestors(id);
+        common.retain(|id| chain.contains(id));
+    }
+
+    common[0]
+}
+
+pub(super) struct DBusMenu {
+    pub(super) layout: Arc<Mutex<Layout>>,
+    pub(super) clicks: Clicks,
+}
+
+#[interface(name = "com.canonical.dbusmenu")]
+impl DBusMenu {
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        recursion_depth: i32,
+        property_names: Vec<String>,
+    ) -> fdo::Result<(u32, Node)> {
+        let layout = self.layout.lock().unwrap();
+        layout.entry(parent_id)?;
+
+        Ok((
+            layout.revision,
+            layout.node(parent_id, recursion_depth, &property_names),
+        ))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        property_names: Vec<String>,
+    ) -> Vec<(i32, Properties)> {
+        let layout = self.layout.lock().unwrap();
+        ids.into_iter()
+            .filter_map(|id| Some((id, layout.entry(id).ok()?.properties(&property_names))))
+            .collect()
+    }
+
+    fn get_property(&self, id: i32, name: String) -> fdo::Result<OwnedValue> {
+        self.layout
+            .lock()
+            .unwrap()
+            .entry(id)?
+            .properties(std::slice::from_ref(&name))
+            .remove(&name)
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("No property {}", name)))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: Value<'_>, _timestamp: u32) -> fdo::Result<()> {
+        let target = self.layout.lock().unwrap().entry(id)?.target;
+        if event_id == "clicked" {
+            let click = match target {
+                Target::Root => return Ok(()),
+                Target::Item(id) => Click::Item(id),
+                Target::Option(id, index) => Click::Option(id, index),
+            };
+            self.clicks.send(click).ok();
+        }
+
+        Ok(())
+    }
+
+    fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+        events
+            .into_iter()
+            .filter_map(|(id, event_id, data, timestamp)| {
+                self.event(id, &event_id, data.into(), timestamp)
+                    .err()
+                    .map(|_| id)
+            })
+            .collect()
+    }
+
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (vec![], vec![])
+    }
+
+    #[zbus(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[zbus(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        vec![]
+    }
+
+    #[zbus(signal)]
+    pub(super) async fn items_properties_updated(
+        emitter: &SignalEmitter<'_>,
+        updated_props: Vec<(i32, Properties)>,
+        removed_props: Vec<(i32, Vec<String>)>,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub(super) async fn layout_updated(
+        emitter: &SignalEmitter<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
@@ -0,0 +1,197 @@
+use {
+    crate::api::sni::{Click, Clicks},
+    crate::{
+        api::animation::Animation,
+        icon::{SniIcon, SniPixmaps as Pixmaps},
+        ScrollOrientation, TIError, Tooltip, TrayEvent, TrayStatus,
+    },
+    std::sync::{Arc, Mutex},
+    zbus::{interface, object_server::SignalEmitter, zvariant::ObjectPath},
+};
+
+pub(super) const PATH: &str = "/StatusNotifierItem";
+pub(super) const INTERFACE: &str = "org.kde.StatusNotifierItem";
+
+// Everything the item exports, shared with `TrayItemLinux` which changes it
+// and emits the matching signal
+pub(super) struct State {
+    pub(super) id: String,
+    pub(super) title: String,
+    pub(super) label: Option<String>,
+    pub(super) icon: SniIcon,
+    pub(super) animation: Option<Animation<SniIcon>>,
+    pub(super) icon_theme_path: String,
+    pub(super) status: TrayStatus,
+    pub(super) overlay_icon: SniIcon,
+    pub(super) attention_icon: SniIcon,
+    pub(super) attention_movie: String,
+    pub(super) tooltip: (SniIcon, String, String),
+}
+
+impl State {
+    fn current_icon(&self) -> &SniIcon {
+        match &self.animation {
+            Some(animation) => animation.current(),
+            None => &self.icon,
+        }
+    }
+}
+
+// The tooltip's icon, title and description
+pub(super) fn tooltip(tooltip: &Tooltip) -> Result<(SniIcon, String, String), TIError> {
+    let icon = match &tooltip.icon {
+        Some(icon) => SniIcon::new(icon)?,
+        None => SniIcon::default(),
+    };
+
+    Ok((icon, tooltip.title.clone(), tooltip.description.clone()))
+}
+
+pub(super) fn status_str(status: TrayStatus) -> &'static str {
+    match status {
+        TrayStatus::Active => "Active",
+        TrayStatus::Passive => "Passive",
+        TrayStatus::NeedsAttention => "NeedsAttention",
+    }
+}
+
+pub(super) struct Item {
+    pub(super) state: Arc<Mutex<State>>,
+    pub(super) clicks: Clicks,
+}
+
+impl Item {
+    fn click(&self, event: TrayEvent) {
+        self.clicks.send(Click::Icon(event)).ok();
+    }
+}
+
+#[interface(name = "org.kde.StatusNotifierItem")]
+impl Item {
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn activate(&self, x: i32, y: i32) {
+        self.click(TrayEvent::IconActivated { x, y });
+    }
+
+    fn secondary_activate(&self, x: i32, y: i32) {
+        self.click(TrayEvent::SecondaryActivated { x, y });
+    }
+
+    fn scroll(&self, delta: i32, orientation: &str) {
+        let orientation = ScrollOrientation::from_sni(orientation);
+        self.click(TrayEvent::Scrolled { delta, orientation });
+    }
+
+    #[zbus(property)]
+    fn category(&self) -> &str {
+        "ApplicationStatus"
+    }
+
+    #[zbus(property)]
+    fn id(&self) -> String {
+        self.state.lock().unwrap().id.clone()
+    }
+
+    #[zbus(property)]
+    fn title(&self) -> String {
+        let state = self.state.lock().unwrap();
+        state.label.as_ref().unwrap_or(&state.title).clone()
+    }
+
+    #[zbus(property)]
+    fn status(&self) -> &str {
+        status_str(self.state.lock().unwrap().status)
+    }
+
+    #[zbus(property)]
+    fn window_id(&self) -> i32 {
+        0
+    }
+
+    #[zbus(property)]
+    fn icon_theme_path(&self) -> String {
+        self.state.lock().unwrap().icon_theme_path.clone()
+    }
+
+    #[zbus(property)]
+    fn icon_name(&self) -> String {
+        self.state.lock().unwrap().current_icon().name.clone()
+    }
+
+    #[zbus(property)]
+    fn icon_pixmap(&self) -> Pixmaps {
+        self.state.lock().unwrap().current_icon().pixmaps.clone()
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_name(&self) -> String {
+        self.state.lock().unwrap().overlay_icon.name.clone()
+    }
+
+    #[zbus(property)]
+    fn overlay_icon_pixmap(&self) -> Pixmaps {
+        self.state.lock().unwrap().overlay_icon.pixmaps.clone()
+    }
+
+    #[zbus(property)]
+    fn attention_icon_name(&self) -> String {
+        self.state.lock().unwrap().attention_icon.name.clone()
+    }
+
+    #[zbus(property)]
+    fn attention_icon_pixmap(&self) -> Pixmaps {
+        self.state.lock().unwrap().attention_icon.pixmaps.clone()
+    }
+
+    #[zbus(property)]
+    fn attention_movie_name(&self) -> String {
+        self.state.lock().unwrap().attention_movie.clone()
+    }
+
+    #[zbus(property)]
+    fn tool_tip(&self) -> (String, Pixmaps, String, String) {
+        let state = self.state.lock().unwrap();
+        let (icon, title, description) = &state.tooltip;
+        (
+            icon.name.clone(),
+            icon.pixmaps.clone(),
+            title.clone(),
+            description.clone(),
+        )
+    }
+
+    #[zbus(property)]
+    fn item_is_menu(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn menu(&self) -> ObjectPath<'_> {
+        ObjectPath::from_static_str_unchecked(super::dbusmenu::PATH)
+    }
+
+    #[zbus(signal)]
+    pub(super) async fn new_title(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub(super) async fn new_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub(super) async fn new_attention_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub(super) async fn new_overlay_icon(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub(super) async fn new_tool_tip(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub(super) async fn new_status(emitter: &SignalEmitter<'_>, status: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    pub(super) async fn new_icon_theme_path(
+        emitter: &SignalEmitter<'_>,
+        icon_theme_path: &str,
+    ) -> zbus::Result<()>;
+}
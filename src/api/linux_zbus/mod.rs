@@ -0,0 +1,363 @@
+// StatusNotifierItem and dbusmenu served straight from the crate over zbus,
+// without C libraries or a thread of our own polling D-Bus
+
+mod dbusmenu;
+mod item;
+
+use {
+    crate::{
+        api::{
+            animation::{self, Animation, Ticker},
+            sni::{self, Click, Clicks, WATCHER},
+        },
+        event::IconAction,
+        icon::SniIcon,
+        menu::SharedMenu,
+        path_str, IconSource, TIError, Tooltip, TrayStatus,
+    },
+    async_io::block_on,
+    dbusmenu::{DBusMenu, Layout},
+    futures_core::Stream,
+    item::{Item, State},
+    std::{
+        borrow::Cow,
+        collections::HashMap,
+        future::poll_fn,
+        path::Path,
+        pin::Pin,
+        process,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        time::Duration,
+    },
+    zbus::{connection, fdo, names::InterfaceName, object_server::SignalEmitter, Connection, Task},
+};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// A property group that changed, each has its own SNI signal
+#[derive(Clone, Copy)]
+enum Changed {
+    Title,
+    Icon,
+    IconThemePath,
+    Status,
+    OverlayIcon,
+    AttentionIcon,
+    ToolTip,
+}
+
+pub struct TrayItemLinux {
+    conn: Connection,
+    state: Arc<Mutex<State>>,
+    menu: SharedMenu,
+    layout: Arc<Mutex<Layout>>,
+    clicks: Clicks,
+    animation: Option<Ticker>,
+    _watcher: Task<()>,
+}
+
+impl TrayItemLinux {
+    pub fn new(title: &str, icon: IconSource, menu: SharedMenu) -> Result<Self, TIError> {
+        block_on(Self::new_async(title, icon, menu))
+    }
+
+    // Connects on the caller's executor, the connection is served by the
+    // executor thread zbus starts for it
+    pub async fn new_async(
+        title: &str,
+        icon: IconSource,
+        menu: SharedMenu,
+    ) -> Result<Self, TIError> {
+        let state = Arc::new(Mutex::new(State {
+            id: title.to_string(),
+            title: title.to_string(),
+            label: None,
+            icon: SniIcon::new(&icon)?,
+            animation: None,
+            icon_theme_path: String::new(),
+            status: TrayStatus::Active,
+            overlay_icon: SniIcon::default(),
+            attention_icon: SniIcon::default(),
+            attention_movie: String::new(),
+            tooltip: Default::default(),
+        }));
+        let layout = Arc::new(Mutex::new(Layout::new(menu.lock().unwrap().items())));
+        let (clicks, rx) = mpsc::channel();
+
+        let name = format!(
+            "org.kde.StatusNotifierItem-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let item = Item {
+            state: Arc::clone(&state),
+            clicks: clicks.clone(),
+        };
+        let dbusmenu = DBusMenu {
+            layout: Arc::clone(&layout),
+            clicks: clicks.clone(),
+        };
+        let conn = async {
+            connection::Builder::session()?
+                .name(name.as_str())?
+                .serve_at(item::PATH, item)?
+                .serve_at(dbusmenu::PATH, dbusmenu)?
+                .build()
+                .await
+        }
+        .await
+        .map_err(error)?;
+
+        // User callbacks never run on the connection's executor
+        let worker = (conn.clone(), Arc::clone(&menu), Arc::clone(&layout));
+        sni::handle_clicks(rx, Arc::clone(&menu), move || {
+            let (conn, menu, layout) = &worker;
+            block_on(update_menu(conn, menu, layout)).ok();
+        });
+
+        let watcher = conn
+            .executor()
+            .spawn(register(conn.clone(), name), "tray-item watcher");
+
+        Ok(Self {
+            conn,
+            state,
+            menu,
+            layout,
+            clicks,
+            animation: None,
+            _watcher: watcher,
+        })
+    }
+
+    pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        block_on(self.set_icon_async(icon))
+    }
+
+    pub fn set_animated_icon(
+        &mut self,
+        frames: Vec<IconSource>,
+        interval: Duration,
+    ) -> Result<(), TIError> {
+        let animation = Animation::new(
+            frames
+                .iter()
+                .map(SniIcon::new)
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+        let frames = animation.frames();
+        self.state.lock().unwrap().animation = Some(animation);
+        self.emit(Changed::Icon)?;
+
+        let conn = self.conn.clone();
+        let state = Arc::clone(&self.state);
+        self.animation = Some(Ticker::start(interval, move || {
+            if animation::advance(&mut state.lock().unwrap().animation, &frames) {
+                block_on(emit(&conn, &state, Changed::Icon)).ok();
+            }
+        }));
+
+        Ok(())
+    }
+
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        self.animation = None;
+        self.state.lock().unwrap().animation = None;
+        self.emit(Changed::Icon)
+    }
+
+    pub fn set_title_label(&mut self, label: Option<&str>) -> Result<(), TIError> {
+        self.state.lock().unwrap().label = label.map(str::to_string);
+        self.emit(Changed::Title)
+    }
+
+    pub fn set_icon_theme_path(&mut self, path: &Path) -> Result<(), TIError> {
+        self.state.lock().unwrap().icon_theme_path = path_str(path)?.to_string();
+        self.emit(Changed::IconThemePath)
+    }
+
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.state.lock().unwrap().status = status;
+        self.emit(Changed::Status)
+    }
+
+    pub fn set_overlay_icon(&mut self, icon: Option<IconSource>) -> Result<(), TIError> {
+        let icon = match icon {
+            Some(icon) => SniIcon::new(&icon)?,
+            None => SniIcon::default(),
+        };
+        self.state.lock().unwrap().overlay_icon = icon;
+        self.emit(Changed::OverlayIcon)
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.state.lock().unwrap().attention_icon = SniIcon::new(&icon)?;
+        self.emit(Changed::AttentionIcon)
+    }
+
+    pub fn set_attention_movie(&mut self, name: &str) -> Result<(), TIError> {
+        self.state.lock().unwrap().attention_movie = name.to_string();
+        self.emit(Changed::AttentionIcon)
+    }
+
+    pub fn set_tooltip(&mut self, tooltip: Tooltip) -> Result<(), TIError> {
+        self.state.lock().unwrap().tooltip = item::tooltip(&tooltip)?;
+        self.emit(Changed::ToolTip)
+    }
+
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
+        block_on(self.update_menu_async())
+    }
+
+    pub(crate) fn enable_icon_action(&mut self, _action: IconAction) -> Result<(), TIError> {
+        // Hosts call every SNI method on the item
+        Ok(())
+    }
+
+    // The async variants run on the caller's executor, only the signal is awaited
+
+    pub async fn set_icon_async(&mut self, icon: IconSource) -> Result<(), TIError> {
+        let icon = SniIcon::new(&icon)?;
+        self.animation = None;
+        {
+            let mut state = self.state.lock().unwrap();
+            state.animation = None;
+            state.icon = icon;
+        }
+
+        emit(&self.conn, &self.state, Changed::Icon)
+            .await
+            .map_err(error)
+    }
+
+    pub async fn update_menu_async(&mut self) -> Result<(), TIError> {
+        update_menu(&self.conn, &self.menu, &self.layout)
+            .await
+            .map_err(error)
+    }
+
+    fn emit(&self, changed: Changed) -> Result<(), TIError> {
+        block_on(emit(&self.conn, &self.state, changed)).map_err(error)
+    }
+}
+
+impl Drop for TrayItemLinux {
+    fn drop(&mut self) {
+        // The click thread holds a connection, which would otherwise keep
+        // serving the interfaces
+        self.clicks.send(Click::Stop).ok();
+        let server = self.conn.object_server();
+        block_on(async {
+            server.remove::<Item, _>(item::PATH).await.ok();
+            server.remove::<DBusMenu, _>(dbusmenu::PATH).await.ok();
+        });
+    }
+}
+
+fn error(e: zbus::Error) -> TIError {
+    TIError::new(format!("D-Bus error: {}", e))
+}
+
+// Sends the SNI signal for `changed`, and PropertiesChanged for hosts that
+// watch the properties instead
+async fn emit(conn: &Connection, state: &Mutex<State>, changed: Changed) -> zbus::Result<()> {
+    let emitter = SignalEmitter::new(conn, item::PATH)?;
+    let properties: &[&str] = match changed {
+        Changed::Title => {
+            Item::new_title(&emitter).await?;
+            &["Title"]
+        }
+        Changed::Icon => {
+            Item::new_icon(&emitter).await?;
+            &["IconName", "IconPixmap"]
+        }
+        Changed::IconThemePath => {
+            let path = state.lock().unwrap().icon_theme_path.clone();
+            Item::new_icon_theme_path(&emitter, &path).await?;
+            &["IconThemePath"]
+        }
+        Changed::Status => {
+            let status = item::status_str(state.lock().unwrap().status);
+            Item::new_status(&emitter, status).await?;
+            &["Status"]
+        }
+        Changed::OverlayIcon => {
+            Item::new_overlay_icon(&emitter).await?;
+            &["OverlayIconName", "OverlayIconPixmap"]
+        }
+        Changed::AttentionIcon => {
+            Item::new_attention_icon(&emitter).await?;
+            &[
+                "AttentionIconName",
+                "AttentionIconPixmap",
+                "AttentionMovieName",
+            ]
+        }
+        Changed::ToolTip => {
+            Item::new_tool_tip(&emitter).await?;
+            &["ToolTip"]
+        }
+    };
+
+    fdo::Properties::properties_changed(
+        &emitter,
+        InterfaceName::from_static_str_unchecked(item::INTERFACE),
+        HashMap::new(),
+        Cow::Borrowed(properties),
+    )
+    .await
+}
+
+// Re-renders the menu and tells hosts what changed: the properties of items
+// that stayed in place, and the part of the layout that did not
+async fn update_menu(
+    conn: &Connection,
+    menu: &SharedMenu,
+    layout: &Mutex<Layout>,
+) -> zbus::Result<()> {
+    let change = layout.lock().unwrap().update(menu.lock().unwrap().items());
+    let emitter = SignalEmitter::new(conn, dbusmenu::PATH)?;
+
+    if !change.properties.is_empty() {
+        DBusMenu::items_properties_updated(&emitter, change.properties, vec![]).await?;
+    }
+    if let Some((revision, parent)) = change.layout {
+        DBusMenu::layout_updated(&emitter, revision, parent).await?;
+    }
+
+    Ok(())
+}
+
+// Registers with the watcher now and again whenever it is replaced, as
+// happens when the panel restarts
+async fn register(conn: Connection, name: String) {
+    let register = || {
+        conn.call_method(
+            Some(WATCHER),
+            "/StatusNotifierWatcher",
+            Some(WATCHER),
+            "RegisterStatusNotifierItem",
+            &name,
+        )
+    };
+
+    let Ok(dbus) = fdo::DBusProxy::new(&conn).await else {
+        return;
+    };
+    let Ok(mut owners) = dbus
+        .receive_name_owner_changed_with_args(&[(0, WATCHER)])
+        .await
+    else {
+        return;
+    };
+    register().await.ok();
+
+    while let Some(change) = poll_fn(|cx| Pin::new(&mut owners).poll_next(cx)).await {
+        if change.args().is_ok_and(|args| args.new_owner().is_some()) {
+            register().await.ok();
+        }
+    }
+}
@@ -1,36 +1,43 @@
 use cocoa::{
     base::id,
-    foundation::{NSData, NSSize},
+    foundation::{NSData, NSInteger, NSSize},
 };
 
 use {
-    crate::IconSource,
-    crate::TIError,
+    crate::{
+        event::IconAction,
+        icon,
+        menu::{self, SharedMenu},
+        Backend, IconSource, MenuItem, MenuItemKind, PixelFormat, Pixmap, TIError, Tooltip,
+        TrayStatus,
+    },
     callback::*,
     cocoa::{
         appkit::{
             NSApp, NSApplication, NSApplicationActivateIgnoringOtherApps, NSImage, NSMenu,
             NSMenuItem, NSRunningApplication, NSStatusBar, NSStatusItem, NSWindow,
         },
-        base::{nil, YES},
+        base::{nil, NO, YES},
         foundation::{NSAutoreleasePool, NSString},
     },
-    objc::{msg_send, sel, sel_impl},
-    std::thread::JoinHandle,
+    objc::{class, msg_send, sel, sel_impl},
+    std::{path::Path, ptr, thread::JoinHandle, time::Duration},
 };
 
 mod callback;
 
-fn get_icon_image(icon: IconSource) -> Option<id> {
+// Pixel icons are drawn at the menu bar height, twice that in pixels on Retina
+const ICON_HEIGHT: f64 = 18.0;
+
+fn get_icon_image(icon: IconSource) -> Result<Option<id>, TIError> {
     unsafe {
         match icon {
-            IconSource::Resource(_) => {
-                let icon = icon.as_str();
+            IconSource::Resource(icon) => {
                 let icon = Some(icon).filter(|icon| !icon.is_empty());
-                icon.map(|icon_name| {
+                Ok(icon.map(|icon_name| {
                     let icon_name = NSString::alloc(nil).init_str(icon_name);
                     NSImage::imageNamed_(NSImage::alloc(nil), icon_name)
-                })
+                }))
             }
             IconSource::Data {
                 height,
@@ -43,46 +50,249 @@ fn get_icon_image(icon: IconSource) -> Option<id> {
                     data.len() as u64,
                 );
                 let image = NSImage::initWithData_(NSImage::alloc(nil), data);
-                let new_size = if width != 0 && height != 0 {
-                    let icon_height: f64 = 18.0;
-                    let icon_width: f64 = (width as f64) / (height as f64 / icon_height);
-                    NSSize::new(icon_width, icon_height)
-                } else {
-                    NSSize::new(18.0, 18.0)
-                };
-                let _: () = msg_send![image, setSize: new_size];
-                Some(image)
+                set_image_size(image, width, height);
+                Ok(Some(image))
+            }
+            IconSource::Pixels {
+                width,
+                height,
+                format,
+                data,
+            } => pixmap_image(&Pixmap {
+                width,
+                height,
+                format,
+                data,
+            })
+            .map(Some),
+            IconSource::Pixmaps(pixmaps) => {
+                let pixmap = icon::closest(&pixmaps, 2 * ICON_HEIGHT as i32)
+                    .ok_or_else(|| TIError::new("The icon has no pixmaps"))?;
+                pixmap_image(pixmap).map(Some)
             }
         }
     }
 }
 
+unsafe fn pixmap_image(pixmap: &Pixmap) -> Result<id, TIError> {
+    let rgba = pixmap.convert(PixelFormat::Rgba8)?;
+
+    // Without planes the rep allocates the buffer itself, the pixels are copied in
+    let rep: id = msg_send![class!(NSBitmapImageRep), alloc];
+    let rep: id = msg_send![rep,
+        initWithBitmapDataPlanes: ptr::null_mut::<*mut u8>()
+        pixelsWide: pixmap.width as NSInteger
+        pixelsHigh: pixmap.height as NSInteger
+        bitsPerSample: 8 as NSInteger
+        samplesPerPixel: 4 as NSInteger
+        hasAlpha: YES
+        isPlanar: NO
+        colorSpaceName: NSString::alloc(nil).init_str("NSDeviceRGBColorSpace")
+        // NSBitmapFormatAlphaNonpremultiplied
+        bitmapFormat: 1u64 << 1
+        bytesPerRow: (pixmap.width * 4) as NSInteger
+        bitsPerPixel: 32 as NSInteger];
+    if rep == nil {
+        return Err(TIError::new("Error creating the icon bitmap"));
+    }
+    let pixels: *mut u8 = msg_send![rep, bitmapData];
+    ptr::copy_nonoverlapping(rgba.as_ptr(), pixels, rgba.len());
+
+    let image: id = msg_send![NSImage::alloc(nil), init];
+    let _: () = msg_send![image, addRepresentation: rep];
+    let _: () = msg_send![rep, release];
+    set_image_size(image, pixmap.width, pixmap.height);
+
+    Ok(image)
+}
+
+unsafe fn set_image_size(image: id, width: i32, height: i32) {
+    let new_size = if width != 0 && height != 0 {
+        let icon_width: f64 = (width as f64) / (height as f64 / ICON_HEIGHT);
+        NSSize::new(icon_width, ICON_HEIGHT)
+    } else {
+        NSSize::new(ICON_HEIGHT, ICON_HEIGHT)
+    };
+    let _: () = msg_send![image, setSize: new_size];
+}
+
+fn build_menu(menu: id, items: &[MenuItem], model: &SharedMenu) {
+    // Enabled state comes from the model instead of the responder chain
+    unsafe {
+        let _: () = msg_send![menu, setAutoenablesItems: NO];
+    }
+
+    for item in items {
+        match item.kind() {
+            MenuItemKind::Label => add_label(menu, item),
+            MenuItemKind::Action | MenuItemKind::Check => add_menu_item(menu, item, model),
+            MenuItemKind::RadioGroup => add_radio_group(menu, item, model),
+            MenuItemKind::Separator => unsafe {
+                let separator = NSMenuItem::separatorItem(nil);
+                set_item_flags(separator, item);
+                NSMenu::addItem_(menu, separator);
+            },
+            MenuItemKind::Submenu => unsafe {
+                let submenu = NSMenu::new(nil).autorelease();
+                build_menu(submenu, item.children(), model);
+
+                let no_key = NSString::alloc(nil).init_str("");
+                let itemtitle = NSString::alloc(nil).init_str(item.label());
+                let menu_item = NSMenuItem::alloc(nil).initWithTitle_action_keyEquivalent_(
+                    itemtitle,
+                    sel!(call),
+                    no_key,
+                );
+                menu_item.setSubmenu_(submenu);
+                set_item_flags(menu_item, item);
+
+                NSMenu::addItem_(menu, menu_item);
+            },
+        }
+    }
+}
+
+fn set_item_flags(menu_item: id, item: &MenuItem) {
+    unsafe {
+        let enabled = if item.is_enabled() { YES } else { NO };
+        let hidden = if item.is_visible() { NO } else { YES };
+        let _: () = msg_send![menu_item, setEnabled: enabled];
+        let _: () = msg_send![menu_item, setHidden: hidden];
+    }
+}
+
+fn add_label(menu: id, label: &MenuItem) {
+    unsafe {
+        let no_key = NSString::alloc(nil).init_str(""); // TODO want this eventually
+        let itemtitle = NSString::alloc(nil).init_str(label.label());
+        let action = sel!(call);
+        let item =
+            NSMenuItem::alloc(nil).initWithTitle_action_keyEquivalent_(itemtitle, action, no_key);
+        let _: () = msg_send![item, setTitle: itemtitle];
+        set_item_flags(item, label);
+
+        NSMenu::addItem_(menu, item);
+    }
+}
+
+fn add_menu_item(menu: id, item: &MenuItem, model: &SharedMenu) {
+    unsafe {
+        let no_key = NSString::alloc(nil).init_str(""); // TODO want this eventually
+        let itemtitle = NSString::alloc(nil).init_str(item.label());
+        let menu_item = NSMenuItem::alloc(nil).initWithTitle_action_keyEquivalent_(
+            itemtitle,
+            sel!(call),
+            no_key,
+        );
+        if item.kind() == MenuItemKind::Check {
+            let _: () = msg_send![menu_item, setState: item.is_checked() as NSInteger];
+        }
+
+        let (model, id) = (SharedMenu::clone(model), item.id());
+        let cb_obj = Callback::from(Box::new(move || {
+            menu::click::activate(&model, id);
+            // Check marks live in the model, mirror the new state on the native item
+            if let Ok(checked) = model.lock().unwrap().is_checked(id) {
+                let _: () = msg_send![menu_item, setState: checked as NSInteger];
+            }
+        }));
+        let _: () = msg_send![menu_item, setTarget: cb_obj];
+        set_item_flags(menu_item, item);
+
+        NSMenu::addItem_(menu, menu_item);
+    }
+}
+
+fn add_radio_group(menu: id, item: &MenuItem, model: &SharedMenu) {
+    unsafe {
+        let options: Vec<id> = item
+            .options()
+            .iter()
+            .enumerate()
+            .map(|(index, option)| {
+                let no_key = NSString::alloc(nil).init_str("");
+                let itemtitle = NSString::alloc(nil).init_str(option);
+                let menu_item = NSMenuItem::alloc(nil).initWithTitle_action_keyEquivalent_(
+                    itemtitle,
+                    sel!(call),
+                    no_key,
+                );
+                let state = (index == item.selected()) as NSInteger;
+                let _: () = msg_send![menu_item, setState: state];
+                set_item_flags(menu_item, item);
+                NSMenu::addItem_(menu, menu_item);
+                menu_item
+            })
+            .collect();
+
+        for (index, menu_item) in options.iter().enumerate() {
+            let (model, id, options) = (SharedMenu::clone(model), item.id(), options.clone());
+            let cb_obj = Callback::from(Box::new(move || {
+                menu::click::select(&model, id, index);
+                for (other, option) in options.iter().enumerate() {
+                    let _: () = msg_send![*option, setState: (other == index) as NSInteger];
+                }
+            }));
+            let _: () = msg_send![*menu_item, setTarget: cb_obj];
+        }
+    }
+}
+
 pub struct TrayItemMacOS {
     name: String,
     menu: *mut objc::runtime::Object,
+    model: SharedMenu,
+    quit_item: Option<String>,
+    tooltip: Option<String>,
+    label: Option<String>,
+    status: TrayStatus,
     _pool: *mut objc::runtime::Object,
     icon: Option<*mut objc::runtime::Object>,
+    attention_icon: Option<*mut objc::runtime::Object>,
+    // Created by `display`, the state above is pushed to it from then on
+    status_item: Option<*mut objc::runtime::Object>,
     main_thread: Option<JoinHandle<()>>,
 }
 
 impl TrayItemMacOS {
-    pub fn new(title: &str, icon: IconSource) -> Result<Self, TIError> {
+    pub fn new(title: &str, icon: IconSource, model: SharedMenu) -> Result<Self, TIError> {
         let t = unsafe {
             let pool = NSAutoreleasePool::new(nil);
 
             TrayItemMacOS {
                 name: title.to_string(),
                 _pool: pool,
-                icon: get_icon_image(icon),
+                icon: get_icon_image(icon)?,
+                attention_icon: None,
                 menu: NSMenu::new(nil).autorelease(),
+                model,
+                quit_item: None,
+                tooltip: None,
+                label: None,
+                status: TrayStatus::Active,
+                status_item: None,
                 main_thread: None,
             }
         };
         Ok(t)
     }
 
+    pub fn with_backend(
+        title: &str,
+        icon: IconSource,
+        model: SharedMenu,
+        _backend: Backend,
+    ) -> Result<Self, TIError> {
+        Self::new(title, icon, model)
+    }
+
+    pub fn backend(&self) -> Backend {
+        Backend::MacOS
+    }
+
     pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
-        self.icon = get_icon_image(icon);
+        self.icon = get_icon_image(icon)?;
+        self.refresh();
         Ok(())
     }
 
@@ -93,39 +303,76 @@ impl TrayItemMacOS {
             let _: () = msg_send![image, setTemplate: YES];
             self.icon = Some(image);
         }
+        self.refresh();
         Ok(())
     }
 
-    pub fn add_label(&mut self, label: &str) -> Result<(), TIError> {
-        unsafe {
-            let no_key = NSString::alloc(nil).init_str(""); // TODO want this eventually
-            let itemtitle = NSString::alloc(nil).init_str(label);
-            let action = sel!(call);
-            let item = NSMenuItem::alloc(nil)
-                .initWithTitle_action_keyEquivalent_(itemtitle, action, no_key);
-            let _: () = msg_send![item, setTitle: itemtitle];
-
-            NSMenu::addItem_(self.menu, item);
-        }
+    pub fn set_overlay_icon(&mut self, _icon: Option<IconSource>) -> Result<(), TIError> {
+        Err(TIError::new("Overlay icons are not supported on macOS"))
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.attention_icon = get_icon_image(icon)?;
+        self.refresh();
+        Ok(())
+    }
+
+    pub fn set_animated_icon(
+        &mut self,
+        _frames: Vec<IconSource>,
+        _interval: Duration,
+    ) -> Result<(), TIError> {
+        Err(TIError::new("Animated icons are not supported on macOS"))
+    }
+
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        Ok(())
+    }
 
+    pub fn set_attention_movie(&mut self, _name: &str) -> Result<(), TIError> {
+        Err(TIError::new("Attention movies are not supported on macOS"))
+    }
+
+    pub fn set_title_label(&mut self, label: Option<&str>) -> Result<(), TIError> {
+        self.label = label.map(str::to_string);
+        self.refresh();
         Ok(())
     }
 
-    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<(), TIError>
-    where
-        F: Fn() -> () + Send + 'static,
-    {
-        let cb_obj = Callback::from(Box::new(cb));
+    pub fn set_icon_theme_path(&mut self, _path: &Path) -> Result<(), TIError> {
+        Err(TIError::new("Icon theme paths are not supported on macOS"))
+    }
+
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.status = status;
+        self.refresh();
 
+        Ok(())
+    }
+
+    pub fn set_tooltip(&mut self, tooltip: Tooltip) -> Result<(), TIError> {
+        self.tooltip = Some(tooltip.text());
+        self.refresh();
+
+        Ok(())
+    }
+
+    pub(crate) fn enable_icon_action(&mut self, action: IconAction) -> Result<(), TIError> {
+        Err(TIError::new(format!(
+            "{:?} is not supported, the status item always opens the menu",
+            action
+        )))
+    }
+
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
         unsafe {
-            let no_key = NSString::alloc(nil).init_str(""); // TODO want this eventually
-            let itemtitle = NSString::alloc(nil).init_str(label);
-            let action = sel!(call);
-            let item = NSMenuItem::alloc(nil)
-                .initWithTitle_action_keyEquivalent_(itemtitle, action, no_key);
-            let _: () = msg_send![item, setTarget: cb_obj];
-
-            NSMenu::addItem_(self.menu, item);
+            let _: () = msg_send![self.menu, removeAllItems];
+        }
+
+        build_menu(self.menu, self.model.lock().unwrap().items(), &self.model);
+
+        if let Some(label) = self.quit_item.clone() {
+            self.add_quit_menu_item(&label);
         }
 
         Ok(())
@@ -133,7 +380,48 @@ impl TrayItemMacOS {
 
     // private
 
+    // The tray is not Send, so setters run on the thread that called `display`,
+    // the main thread AppKit wants status items changed on
+    fn refresh(&self) {
+        let Some(item) = self.status_item else {
+            return;
+        };
+
+        unsafe {
+            let icon = match self.status {
+                TrayStatus::NeedsAttention => self.attention_icon.or(self.icon),
+                _ => self.icon,
+            };
+            let title = match icon {
+                // The label sits next to the image
+                Some(_) => self.label.as_deref().unwrap_or(""),
+                None => self.name.as_str(),
+            };
+            let _: () = msg_send![item, setImage: icon.unwrap_or(nil)];
+            item.setTitle_(NSString::alloc(nil).init_str(title));
+
+            let button: id = msg_send![item, button];
+            let tooltip = match &self.tooltip {
+                Some(tooltip) => NSString::alloc(nil).init_str(tooltip),
+                None => nil,
+            };
+            let _: () = msg_send![button, setToolTip: tooltip];
+
+            let visible = if self.status == TrayStatus::Passive {
+                NO
+            } else {
+                YES
+            };
+            let _: () = msg_send![item, setVisible: visible];
+        }
+    }
+
     pub fn add_quit_item(&mut self, label: &str) {
+        self.quit_item = Some(label.to_string());
+        self.add_quit_menu_item(label);
+    }
+
+    fn add_quit_menu_item(&self, label: &str) {
         unsafe {
             let no_key = NSString::alloc(nil).init_str("");
             let pref_item = NSString::alloc(nil).init_str(label);
@@ -154,12 +442,9 @@ impl TrayItemMacOS {
             app.activateIgnoringOtherApps_(YES);
 
             let item = NSStatusBar::systemStatusBar(nil).statusItemWithLength_(-1.0);
-            let title = NSString::alloc(nil).init_str(&self.name);
-            if let Some(icon) = self.icon {
-                let _: () = msg_send![item, setImage: icon];
-            } else {
-                item.setTitle_(title);
-            }
+            let _: () = msg_send![item, retain];
+            self.status_item = Some(item);
+            self.refresh();
             item.setMenu_(self.menu);
 
             let current_app = NSRunningApplication::currentApplication(nil);
@@ -0,0 +1,277 @@
+use {
+    crate::{
+        event::{IconAction, SharedEvents},
+        icon,
+        menu::{self, SharedMenu},
+        Backend, IconSource, Menu, MenuItemId, ScrollOrientation, TIError, Tooltip, TrayEvent,
+        TrayStatus,
+    },
+    std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+};
+
+/// Backend that only records what it is told to show, so tray logic can be
+/// tested without a desktop. Reach it through [`crate::TrayItem::inner_mut`].
+pub struct TrayItemMock {
+    title: String,
+    label: Option<String>,
+    icon: IconSource,
+    animation: Option<(Vec<IconSource>, Duration)>,
+    icon_theme_path: Option<PathBuf>,
+    status: TrayStatus,
+    overlay_icon: Option<IconSource>,
+    attention_icon: Option<IconSource>,
+    attention_movie: Option<String>,
+    tooltip: Tooltip,
+    menu: SharedMenu,
+    menu_updates: usize,
+    events: SharedEvents,
+}
+
+impl TrayItemMock {
+    pub fn new(title: &str, icon: IconSource, menu: SharedMenu) -> Result<Self, TIError> {
+        check_icon(&icon)?;
+        let events = SharedEvents::clone(menu.lock().unwrap().events());
+
+        Ok(Self {
+            title: title.to_string(),
+            label: None,
+            icon,
+            animation: None,
+            icon_theme_path: None,
+            status: TrayStatus::Active,
+            overlay_icon: None,
+            attention_icon: None,
+            attention_movie: None,
+            tooltip: Tooltip::default(),
+            menu,
+            menu_updates: 0,
+            events,
+        })
+    }
+
+    // Stands in for whichever backend was asked for
+    #[cfg(feature = "async")]
+    pub async fn new_async(
+        title: &str,
+        icon: IconSource,
+        menu: SharedMenu,
+    ) -> Result<Self, TIError> {
+        Self::new(title, icon, menu)
+    }
+
+    pub fn with_backend(
+        title: &str,
+        icon: IconSource,
+        menu: SharedMenu,
+        _backend: Backend,
+    ) -> Result<Self, TIError> {
+        Self::new(title, icon, menu)
+    }
+
+    pub fn backend(&self) -> Backend {
+        Backend::Mock
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn icon(&self) -> &IconSource {
+        &self.icon
+    }
+
+    /// Frames and interval of the running animation.
+    pub fn animation(&self) -> Option<(&[IconSource], Duration)> {
+        self.animation
+            .as_ref()
+            .map(|(frames, interval)| (frames.as_slice(), *interval))
+    }
+
+    pub fn icon_theme_path(&self) -> Option<&Path> {
+        self.icon_theme_path.as_deref()
+    }
+
+    pub fn status(&self) -> TrayStatus {
+        self.status
+    }
+
+    pub fn overlay_icon(&self) -> Option<&IconSource> {
+        self.overlay_icon.as_ref()
+    }
+
+    pub fn attention_icon(&self) -> Option<&IconSource> {
+        self.attention_icon.as_ref()
+    }
+
+    pub fn attention_movie(&self) -> Option<&str> {
+        self.attention_movie.as_deref()
+    }
+
+    pub fn tooltip(&self) -> &Tooltip {
+        &self.tooltip
+    }
+
+    /// Snapshot of the whole menu tree.
+    /// The copy is detached, its ids and callbacks do nothing on this tray.
+    pub fn menu(&self) -> Menu {
+        self.menu.lock().unwrap().clone()
+    }
+
+    /// How many times the menu was told to re-render.
+    pub fn menu_updates(&self) -> usize {
+        self.menu_updates
+    }
+
+    /// Clicks a menu item, running its callback before returning.
+    pub fn activate(&mut self, id: MenuItemId) -> Result<(), TIError> {
+        menu::click::try_activate(&self.menu, id)
+    }
+
+    /// Picks option `index` of a radio group, running its callback before returning.
+    pub fn select(&mut self, id: MenuItemId, index: usize) -> Result<(), TIError> {
+        menu::click::try_select(&self.menu, id, index)
+    }
+
+    pub fn activate_icon(&mut self, x: i32, y: i32) {
+        self.events.send(TrayEvent::IconActivated { x, y });
+    }
+
+    pub fn secondary_activate_icon(&mut self, x: i32, y: i32) {
+        self.events.send(TrayEvent::SecondaryActivated { x, y });
+    }
+
+    pub fn scroll(&mut self, delta: i32, orientation: ScrollOrientation) {
+        self.events.send(TrayEvent::Scrolled { delta, orientation });
+    }
+
+    pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        check_icon(&icon)?;
+        self.animation = None;
+        self.icon = icon;
+
+        Ok(())
+    }
+
+    pub fn set_animated_icon(
+        &mut self,
+        frames: Vec<IconSource>,
+        interval: Duration,
+    ) -> Result<(), TIError> {
+        frames.iter().try_for_each(check_icon)?;
+        self.animation = Some((frames, interval));
+
+        Ok(())
+    }
+
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        self.animation = None;
+
+        Ok(())
+    }
+
+    pub fn set_title_label(&mut self, label: Option<&str>) -> Result<(), TIError> {
+        self.label = label.map(str::to_string);
+
+        Ok(())
+    }
+
+    pub fn set_icon_theme_path(&mut self, path: &Path) -> Result<(), TIError> {
+        self.icon_theme_path = Some(path.to_path_buf());
+
+        Ok(())
+    }
+
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.status = status;
+
+        Ok(())
+    }
+
+    pub fn set_overlay_icon(&mut self, icon: Option<IconSource>) -> Result<(), TIError> {
+        if let Some(icon) = &icon {
+            check_icon(icon)?;
+        }
+        self.overlay_icon = icon;
+
+        Ok(())
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        check_icon(&icon)?;
+        self.attention_icon = Some(icon);
+
+        Ok(())
+    }
+
+    pub fn set_attention_movie(&mut self, name: &str) -> Result<(), TIError> {
+        self.attention_movie = Some(name.to_string());
+
+        Ok(())
+    }
+
+    pub fn set_tooltip(&mut self, tooltip: Tooltip) -> Result<(), TIError> {
+        if let Some(icon) = &tooltip.icon {
+            check_icon(icon)?;
+        }
+        self.tooltip = tooltip;
+
+        Ok(())
+    }
+
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
+        self.menu_updates += 1;
+
+        Ok(())
+    }
+
+    pub(crate) fn enable_icon_action(&mut self, _action: IconAction) -> Result<(), TIError> {
+        Ok(())
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn set_icon_async(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.set_icon(icon)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn update_menu_async(&mut self) -> Result<(), TIError> {
+        self.update_menu()
+    }
+}
+
+// Rejects the pixel data a real backend would, so tests catch it too
+fn check_icon(icon: &IconSource) -> Result<(), TIError> {
+    match icon {
+        IconSource::Pixels {
+            width,
+            height,
+            format,
+            data,
+        } => icon::convert(*width, *height, *format, *format, data).map(drop),
+        IconSource::Pixmaps(pixmaps) => pixmaps
+            .iter()
+            .try_for_each(|pixmap| pixmap.convert(pixmap.format).map(drop)),
+        #[cfg(all(
+            target_os = "linux",
+            any(feature = "ksni", feature = "zbus", feature = "xembed")
+        ))]
+        IconSource::Data {
+            width,
+            height,
+            data,
+        } => {
+            let argb = icon::PixelFormat::Argb32;
+            icon::convert(*width, *height, argb, argb, data).map(drop)
+        }
+        #[cfg(target_os = "macos")]
+        IconSource::Data { data, .. } if data.is_empty() => Err(TIError::new("Icon data is empty")),
+        _ => Ok(()),
+    }
+}
@@ -1,24 +1,62 @@
-#[cfg(all(target_os = "linux", feature = "ksni"))]
+// Frames of animated icons for the backends that cycle them on their own
+#[cfg(all(
+    any(
+        target_os = "windows",
+        all(
+            target_os = "linux",
+            any(feature = "ksni", feature = "zbus", feature = "xembed")
+        )
+    ),
+    not(feature = "mock")
+))]
+mod animation;
+
+// The mock backend replaces the platform one when enabled
+#[cfg(feature = "mock")]
+mod mock;
+
+#[cfg(all(target_os = "linux", not(feature = "mock")))]
+mod linux;
+
+// Shared by the two StatusNotifierItem backends
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus"),
+    not(feature = "mock")
+))]
+mod sni;
+
+#[cfg(all(target_os = "linux", feature = "ksni", not(feature = "mock")))]
 mod linux_ksni;
 
-#[cfg(all(target_os = "linux", feature = "libappindicator"))]
+#[cfg(all(target_os = "linux", feature = "zbus", not(feature = "mock")))]
+mod linux_zbus;
+
+#[cfg(all(
+    target_os = "linux",
+    feature = "libappindicator",
+    not(feature = "mock")
+))]
 mod linux_libappindicator;
 
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "linux", feature = "xembed", not(feature = "mock")))]
+mod linux_xembed;
+
+#[cfg(all(target_os = "windows", not(feature = "mock")))]
 mod windows;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "macos", not(feature = "mock")))]
 mod macos;
 
 // Set type depending on OS and feature
-#[cfg(all(target_os = "linux", feature = "ksni"))]
-pub type TrayItemImpl = linux_ksni::TrayItemLinux;
+#[cfg(feature = "mock")]
+pub type TrayItemImpl = mock::TrayItemMock;
 
-#[cfg(all(target_os = "linux", feature = "libappindicator"))]
-pub type TrayItemImpl = linux_libappindicator::TrayItemLinux;
+#[cfg(all(target_os = "linux", not(feature = "mock")))]
+pub type TrayItemImpl = linux::TrayItemLinux;
 
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", not(feature = "mock")))]
 pub type TrayItemImpl = windows::TrayItemWindows;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "macos", not(feature = "mock")))]
 pub type TrayItemImpl = macos::TrayItemMacOS;
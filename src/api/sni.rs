@@ -0,0 +1,107 @@
+// What the ksni and zbus backends share, both serve a StatusNotifierItem
+
+use {
+    crate::{
+        event::SharedEvents,
+        menu::{self, SharedMenu},
+        MenuItemId, TrayEvent,
+    },
+    std::{sync::mpsc, thread},
+};
+
+#[cfg(all(feature = "async", feature = "ksni"))]
+use crate::TIError;
+
+pub(super) const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+
+// Both backends answer D-Bus with their state locked, so clicks are handled on
+// a thread of their own where user callbacks are free to update the tray
+pub(super) enum Click {
+    Icon(TrayEvent),
+    Item(MenuItemId),
+    Option(MenuItemId, usize),
+    Stop,
+}
+
+pub(super) type Clicks = mpsc::Sender<Click>;
+
+// Runs until `Click::Stop` or until every sender is gone, `refresh` shows the
+// menu again after a click changed it
+pub(super) fn handle_clicks<F>(clicks: mpsc::Receiver<Click>, menu: SharedMenu, refresh: F)
+where
+    F: Fn() + Send + 'static,
+{
+    let events = SharedEvents::clone(menu.lock().unwrap().events());
+    thread::spawn(move || {
+        for click in clicks {
+            let revision = menu.lock().unwrap().revision();
+            match click {
+                Click::Icon(event) => {
+                    events.send(event);
+                    continue;
+                }
+                Click::Item(id) => menu::click::activate(&menu, id),
+                Click::Option(id, index) => menu::click::select(&menu, id, index),
+                Click::Stop => break,
+            }
+            // Check marks live in the model, show the new state
+            if menu.lock().unwrap().revision() != revision {
+                refresh();
+            }
+        }
+    });
+}
+
+// Whether a StatusNotifierWatcher runs on the session bus, no host shows the
+// item without one
+#[cfg(feature = "zbus")]
+pub(super) fn watcher_running() -> bool {
+    async_io::block_on(watcher_running_async())
+}
+
+#[cfg(feature = "zbus")]
+pub(super) async fn watcher_running_async() -> bool {
+    use zbus::{fdo, names::BusName, Connection};
+
+    async {
+        let conn = Connection::session().await?;
+        let name = BusName::try_from(WATCHER).map_err(zbus::Error::from)?;
+        fdo::DBusProxy::new(&conn).await?.name_has_owner(name).await
+    }
+    .await
+    .unwrap_or(false)
+}
+
+#[cfg(not(feature = "zbus"))]
+pub(super) fn watcher_running() -> bool {
+    let Ok(conn) = dbus::blocking::Connection::new_session() else {
+        return false;
+    };
+    conn.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        std::time::Duration::from_secs(1),
+    )
+    .method_call("org.freedesktop.DBus", "NameHasOwner", (WATCHER,))
+    .is_ok_and(|(running,)| running)
+}
+
+#[cfg(all(feature = "async", not(feature = "zbus")))]
+pub(super) async fn watcher_running_async() -> bool {
+    unblock(|| Ok(watcher_running())).await.unwrap_or(false)
+}
+
+// Runs blocking D-Bus calls on a thread of their own, so async callers never
+// block their executor on them
+#[cfg(all(feature = "async", feature = "ksni"))]
+pub(super) async fn unblock<T, F>(f: F) -> Result<T, TIError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, TIError> + Send + 'static,
+{
+    let (tx, rx) = futures_channel::oneshot::channel();
+    thread::spawn(move || tx.send(f()).ok());
+
+    rx.await
+        .map_err(|_| TIError::new("The tray service has stopped"))?
+}
@@ -1,23 +1,67 @@
-use std::{ffi::OsStr, mem, os::windows::ffi::OsStrExt, ptr};
+use std::{ffi::OsStr, mem, os::windows::ffi::OsStrExt, ptr, sync::atomic::Ordering};
 
 use windows_sys::Win32::{
     Foundation::{GetLastError, HWND, LRESULT, POINT},
     System::LibraryLoader::GetModuleHandleW,
     UI::{
-        Shell::{NIF_MESSAGE, NIM_ADD, NIF_ICON},
+        Shell::{NIF_ICON, NIF_MESSAGE, NIM_ADD},
         WindowsAndMessaging::{
-            CreatePopupMenu, CreateWindowExW, DefWindowProcW, DispatchMessageW, GetCursorPos,
-            GetMenuItemID, GetMessageW, PostQuitMessage, RegisterClassW, SetForegroundWindow,
-            SetMenuInfo, TrackPopupMenu, TranslateMessage, CW_USEDEFAULT, MENUINFO,
-            MIM_APPLYTOSUBMENUS, MIM_STYLE, MNS_NOTIFYBYPOS, MSG, TPM_BOTTOMALIGN, TPM_LEFTALIGN,
-            TPM_LEFTBUTTON, WM_LBUTTONUP, WM_MENUCOMMAND, WM_QUIT, WM_RBUTTONUP, WM_USER,
-            WNDCLASSW, WS_OVERLAPPEDWINDOW, WM_CREATE, HICON, IDI_APPLICATION, LoadIconW, 
-            RegisterWindowMessageW,
+            CreateIcon, CreatePopupMenu, CreateWindowExW, DefWindowProcW, DeleteMenu,
+            DispatchMessageW, GetCursorPos, GetMenuItemCount, GetMenuItemID, GetMessageW,
+            InsertMenuItemW, LoadIconW, PostQuitMessage, RegisterClassW, RegisterWindowMessageW,
+            SetForegroundWindow, SetMenuInfo, TrackPopupMenu, TranslateMessage, CW_USEDEFAULT,
+            HICON, HMENU, IDI_APPLICATION, MENUINFO, MENUITEMINFOW, MFS_CHECKED, MFS_DISABLED,
+            MFS_UNHILITE, MFT_RADIOCHECK, MFT_SEPARATOR, MFT_STRING, MF_BYPOSITION, MIIM_FTYPE,
+            MIIM_ID, MIIM_STATE, MIIM_STRING, MIIM_SUBMENU, MIM_APPLYTOSUBMENUS, MIM_STYLE,
+            MNS_NOTIFYBYPOS, MSG, TPM_BOTTOMALIGN, TPM_LEFTALIGN, TPM_LEFTBUTTON, WM_CREATE,
+            WM_LBUTTONUP, WM_MBUTTONUP, WM_MENUCOMMAND, WM_QUIT, WM_RBUTTONUP, WM_USER, WNDCLASSW,
+            WS_OVERLAPPEDWINDOW,
         },
     },
 };
 
-use {super::*, crate::TIError};
+use {
+    super::*,
+    crate::{Menu, MenuItem, MenuItemKind, PixelFormat, Pixmap, TIError},
+};
+
+// Transparency comes from the alpha channel, so the AND mask is left empty
+pub(crate) unsafe fn create_icon(pixmap: &Pixmap) -> Result<HICON, TIError> {
+    let bgra = pixmap.convert(PixelFormat::Bgra8)?;
+    // Mask rows are padded to 16 bits
+    let mask = vec![0u8; (pixmap.width as usize + 15) / 16 * 2 * pixmap.height as usize];
+
+    let icon = CreateIcon(
+        0,
+        pixmap.width,
+        pixmap.height,
+        1,
+        32,
+        mask.as_ptr(),
+        bgra.as_ptr(),
+    );
+    if icon == 0 {
+        return Err(get_win_os_error("Error creating icon"));
+    }
+
+    Ok(icon)
+}
+
+// Also used by the animation thread, which has no access to the tray
+pub(crate) unsafe fn set_notify_icon(hwnd: HWND, icon: HICON) -> Result<(), TIError> {
+    let mut nid = mem::zeroed::<NOTIFYICONDATAW>();
+    nid.cbSize = mem::size_of::<NOTIFYICONDATAW>() as u32;
+    nid.hWnd = hwnd;
+    nid.uID = 1;
+    nid.uFlags = NIF_ICON;
+    nid.hIcon = icon;
+
+    if Shell_NotifyIconW(NIM_MODIFY, &nid) == 0 {
+        return Err(get_win_os_error("Error setting icon"));
+    }
+
+    Ok(())
+}
 
 pub(crate) fn to_wstring(str: &str) -> Vec<u16> {
     OsStr::new(str)
@@ -47,15 +91,39 @@ pub(crate) unsafe extern "system" fn window_proc(
             let stash = stash.borrow();
             let stash = stash.as_ref();
             if let Some(stash) = stash {
-                let menu_id = GetMenuItemID(stash.info.hmenu, w_param as i32) as i32;
+                // l_param is the (sub)menu the clicked item belongs to
+                let menu_id = GetMenuItemID(l_param as HMENU, w_param as i32) as i32;
                 if menu_id != -1 {
-                    stash.tx.send(WindowsTrayEvent(menu_id as u32)).ok();
+                    stash
+                        .tx
+                        .send(WindowsTrayEvent::MenuItem(menu_id as u32))
+                        .ok();
                 }
             }
         });
     }
 
-    if msg == WM_USER + 1 && (l_param as u32 == WM_LBUTTONUP || l_param as u32 == WM_RBUTTONUP) {
+    if msg == WM_USER + 1 && (l_param as u32 == WM_LBUTTONUP || l_param as u32 == WM_MBUTTONUP) {
+        let mut point = POINT { x: 0, y: 0 };
+        GetCursorPos(&mut point);
+
+        WININFO_STASH.with(|stash| {
+            let stash = stash.borrow();
+            let stash = stash.as_ref();
+            if let Some(stash) = stash {
+                let event = if l_param as u32 == WM_LBUTTONUP {
+                    WindowsTrayEvent::Activated(point.x, point.y)
+                } else {
+                    WindowsTrayEvent::SecondaryActivated(point.x, point.y)
+                };
+                stash.tx.send(event).ok();
+            }
+        });
+    }
+
+    if msg == WM_USER + 1
+        && ((l_param as u32 == WM_LBUTTONUP && left_click_menu()) || l_param as u32 == WM_RBUTTONUP)
+    {
         let mut point = POINT { x: 0, y: 0 };
         if GetCursorPos(&mut point) == 0 {
             return 1;
@@ -119,6 +187,16 @@ pub(crate) unsafe extern "system" fn window_proc(
     DefWindowProcW(h_wnd, msg, w_param, l_param)
 }
 
+// Whether a left click on the icon should open the menu
+fn left_click_menu() -> bool {
+    WININFO_STASH.with(|stash| {
+        stash
+            .borrow()
+            .as_ref()
+            .map_or(true, |stash| stash.left_click_menu.load(Ordering::Relaxed))
+    })
+}
+
 pub(crate) unsafe fn init_window() -> Result<WindowInfo, TIError> {
     let hmodule = GetModuleHandleW(ptr::null());
     if hmodule == 0 {
@@ -176,6 +254,17 @@ pub(crate) unsafe fn init_window() -> Result<WindowInfo, TIError> {
     }
 
     // Setup menu
+    let hmenu = create_popup_menu()?;
+
+    Ok(WindowInfo {
+        hwnd,
+        hmenu,
+        hmodule,
+    })
+}
+
+// Every (sub)menu needs MNS_NOTIFYBYPOS for WM_MENUCOMMAND to report positions
+pub(crate) unsafe fn create_popup_menu() -> Result<HMENU, TIError> {
     let mut info = unsafe { mem::zeroed::<MENUINFO>() };
     info.cbSize = mem::size_of::<MENUINFO>() as u32;
     info.fMask = MIM_APPLYTOSUBMENUS | MIM_STYLE;
@@ -188,11 +277,133 @@ pub(crate) unsafe fn init_window() -> Result<WindowInfo, TIError> {
         return Err(get_win_os_error("Error setting up menu"));
     }
 
-    Ok(WindowInfo {
-        hwnd,
-        hmenu,
-        hmodule,
-    })
+    Ok(hmenu)
+}
+
+pub(crate) unsafe fn render_menu(
+    info: &WindowInfo,
+    menu: &SharedMenu,
+    entries: &Mutex<Vec<MenuEntry>>,
+) -> Result<(), TIError> {
+    // The event loop re-renders after clicks while the user may be changing
+    // the menu, holding the entries for the whole render keeps them apart
+    let mut entries = entries.lock().unwrap();
+
+    // Deleting an item that opens a submenu destroys the submenu as well
+    for _ in 0..GetMenuItemCount(info.hmenu) {
+        if DeleteMenu(info.hmenu, 0, MF_BYPOSITION) == 0 {
+            return Err(get_win_os_error("Error removing menu item"));
+        }
+    }
+
+    let menu: &Menu = &menu.lock().unwrap();
+    let mut ids = Vec::new();
+    insert_menu_items(info.hmenu, menu.items(), &mut ids)?;
+    *entries = ids;
+
+    Ok(())
+}
+
+unsafe fn insert_menu_items(
+    hmenu: HMENU,
+    items: &[MenuItem],
+    ids: &mut Vec<MenuEntry>,
+) -> Result<(), TIError> {
+    // Radio options are flattened into the parent, so positions are counted separately
+    let mut pos = 0;
+    for menu_item in items.iter().filter(|item| item.is_visible()) {
+        if menu_item.kind() == MenuItemKind::RadioGroup {
+            for (index, option) in menu_item.options().iter().enumerate() {
+                let item_idx = ids.len() as u32;
+                ids.push(MenuEntry {
+                    id: menu_item.id(),
+                    option: Some(index),
+                });
+
+                let mut st = to_wstring(option);
+                let mut item = unsafe { mem::zeroed::<MENUITEMINFOW>() };
+                item.cbSize = mem::size_of::<MENUITEMINFOW>() as u32;
+                item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
+                item.fType = MFT_STRING | MFT_RADIOCHECK;
+                if index == menu_item.selected() {
+                    item.fState = MFS_CHECKED;
+                }
+                if !menu_item.is_enabled() {
+                    item.fState |= MFS_DISABLED;
+                }
+                item.wID = item_idx;
+                item.dwTypeData = st.as_mut_ptr();
+                item.cch = (option.len() * 2) as u32;
+
+                if InsertMenuItemW(hmenu, pos, 1, &item) == 0 {
+                    return Err(get_win_os_error("Error inserting menu item"));
+                }
+                pos += 1;
+            }
+            continue;
+        }
+
+        let item_idx = ids.len() as u32;
+        ids.push(MenuEntry {
+            id: menu_item.id(),
+            option: None,
+        });
+
+        let mut st = to_wstring(menu_item.label());
+        let mut item = unsafe { mem::zeroed::<MENUITEMINFOW>() };
+        item.cbSize = mem::size_of::<MENUITEMINFOW>() as u32;
+        item.wID = item_idx;
+
+        match menu_item.kind() {
+            MenuItemKind::Label => {
+                item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
+                item.fType = MFT_STRING;
+                item.fState = MFS_UNHILITE;
+                item.dwTypeData = st.as_mut_ptr();
+                item.cch = (menu_item.label().len() * 2) as u32;
+            }
+            MenuItemKind::Action => {
+                item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
+                item.fType = MFT_STRING;
+                item.dwTypeData = st.as_mut_ptr();
+                item.cch = (menu_item.label().len() * 2) as u32;
+            }
+            MenuItemKind::Check => {
+                item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
+                item.fType = MFT_STRING;
+                if menu_item.is_checked() {
+                    item.fState = MFS_CHECKED;
+                }
+                item.dwTypeData = st.as_mut_ptr();
+                item.cch = (menu_item.label().len() * 2) as u32;
+            }
+            MenuItemKind::RadioGroup => unreachable!(),
+            MenuItemKind::Separator => {
+                item.fMask = MIIM_FTYPE | MIIM_ID | MIIM_STATE;
+                item.fType = MFT_SEPARATOR;
+            }
+            MenuItemKind::Submenu => {
+                let submenu = create_popup_menu()?;
+                insert_menu_items(submenu, menu_item.children(), ids)?;
+
+                item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE | MIIM_SUBMENU;
+                item.fType = MFT_STRING;
+                item.hSubMenu = submenu;
+                item.dwTypeData = st.as_mut_ptr();
+                item.cch = (menu_item.label().len() * 2) as u32;
+            }
+        }
+        if !menu_item.is_enabled() {
+            item.fState |= MFS_DISABLED;
+        }
+
+        if InsertMenuItemW(hmenu, pos, 1, &item) == 0 {
+            return Err(get_win_os_error("Error inserting menu item"));
+        }
+        pos += 1;
+    }
+
+    Ok(())
 }
 
 pub(crate) unsafe fn run_loop() {
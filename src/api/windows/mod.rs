@@ -6,36 +6,53 @@ mod structs;
 use std::{
     cell::RefCell,
     mem,
+    path::Path,
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{channel, Sender},
         Arc, Mutex,
     },
     thread,
+    time::Duration,
 };
 
 use windows_sys::Win32::{
     Foundation::{LPARAM, WPARAM},
     UI::{
-        Shell::{Shell_NotifyIconW, NIF_ICON, NIF_TIP, NIM_DELETE, NIM_MODIFY, NOTIFYICONDATAW},
+        Shell::{
+            Shell_NotifyIconW, NIF_ICON, NIF_STATE, NIF_TIP, NIM_DELETE, NIM_MODIFY, NIS_HIDDEN,
+            NOTIFYICONDATAW,
+        },
         WindowsAndMessaging::{
-            InsertMenuItemW, LoadImageW, PostMessageW, SetMenuItemInfoW, HICON, IMAGE_ICON,
-            LR_DEFAULTCOLOR, MENUITEMINFOW, MFS_DISABLED, MFS_UNHILITE, MFT_SEPARATOR, MFT_STRING,
-            MIIM_FTYPE, MIIM_ID, MIIM_STATE, MIIM_STRING, WM_DESTROY,
+            GetSystemMetrics, LoadImageW, PostMessageW, HICON, IMAGE_ICON, LR_DEFAULTCOLOR,
+            SM_CXSMICON, WM_DESTROY,
         },
     },
 };
 
-use crate::{IconSource, TIError};
+use crate::{
+    api::animation::Ticker,
+    event::{IconAction, SharedEvents},
+    icon::{self, Pixmap},
+    menu::{self, SharedMenu},
+    Backend, IconSource, TIError, Tooltip, TrayEvent, TrayStatus,
+};
 
 use funcs::*;
 use structs::*;
 
 thread_local!(static WININFO_STASH: RefCell<Option<WindowsLoopData>> = RefCell::new(None));
 
-type CallBackEntry = Option<Box<dyn Fn() + Send + 'static>>;
-
 pub struct TrayItemWindows {
-    entries: Arc<Mutex<Vec<CallBackEntry>>>,
+    // Maps the wID of every rendered menu item to its entry in the menu model
+    entries: Arc<Mutex<Vec<MenuEntry>>>,
+    menu: SharedMenu,
+    left_click_menu: Arc<AtomicBool>,
+    // Remembered so the normal icon comes back once attention is no longer needed
+    icon: Icon,
+    attention_icon: Option<Icon>,
+    status: TrayStatus,
+    animation: Option<Ticker>,
     info: WindowInfo,
     windows_loop: Option<thread::JoinHandle<()>>,
     event_loop: Option<thread::JoinHandle<()>>,
@@ -43,29 +60,16 @@ pub struct TrayItemWindows {
 }
 
 impl TrayItemWindows {
-    pub fn new(title: &str, icon: IconSource) -> Result<Self, TIError> {
+    pub fn new(title: &str, icon: IconSource, menu: SharedMenu) -> Result<Self, TIError> {
         let entries = Arc::new(Mutex::new(Vec::new()));
         let (event_tx, event_rx) = channel::<WindowsTrayEvent>();
 
-        let entries_clone = Arc::clone(&entries);
-        let event_loop = thread::spawn(move || loop {
-            if let Ok(v) = event_rx.recv() {
-                if v.0 == u32::MAX {
-                    break;
-                }
-
-                padlock::mutex_lock(&entries_clone, |ents: &mut Vec<CallBackEntry>| match &ents
-                    [v.0 as usize]
-                {
-                    Some(f) => f(),
-                    None => (),
-                })
-            }
-        });
-
         let (tx, rx) = channel();
 
+        let left_click_menu = Arc::new(AtomicBool::new(true));
+
         let event_tx_clone = event_tx.clone();
+        let left_click_menu_clone = Arc::clone(&left_click_menu);
         let windows_loop = thread::spawn(move || unsafe {
             let info = match init_window() {
                 Ok(info) => {
@@ -83,6 +87,7 @@ impl TrayItemWindows {
                 let data = WindowsLoopData {
                     info,
                     tx: event_tx_clone,
+                    left_click_menu: left_click_menu_clone,
                 };
 
                 (*stash.borrow_mut()) = Some(data);
@@ -96,161 +101,194 @@ impl TrayItemWindows {
             Err(e) => return Err(e),
         };
 
-        let w = Self {
+        let info_clone = info.clone();
+        let menu_clone = Arc::clone(&menu);
+        let entries_clone = Arc::clone(&entries);
+        let events = SharedEvents::clone(menu.lock().unwrap().events());
+        let event_loop = thread::spawn(move || loop {
+            match event_rx.recv() {
+                Ok(WindowsTrayEvent::MenuItem(item_idx)) => {
+                    let entry = padlock::mutex_lock(&entries_clone, |ents: &mut Vec<MenuEntry>| {
+                        ents.get(item_idx as usize).copied()
+                    });
+
+                    if let Some(entry) = entry {
+                        match entry.option {
+                            Some(index) => {
+                                menu::click::select(&menu_clone, entry.id, index)
+                            }
+                            None => menu::click::activate(&menu_clone, entry.id),
+                        }
+                        // Check marks live in the model, redraw so the menu shows the new state
+                        unsafe {
+                            render_menu(&info_clone, &menu_clone, &entries_clone).ok();
+                        }
+                    }
+                }
+                Ok(WindowsTrayEvent::Activated(x, y)) => {
+                    events.send(TrayEvent::IconActivated { x, y });
+                }
+                Ok(WindowsTrayEvent::SecondaryActivated(x, y)) => {
+                    events.send(TrayEvent::SecondaryActivated { x, y });
+                }
+                Ok(WindowsTrayEvent::Quit) | Err(_) => break,
+            }
+        });
+
+        let mut w = Self {
             entries,
+            menu,
+            left_click_menu,
+            icon: Icon {
+                handle: 0,
+                owned: false,
+            },
+            attention_icon: None,
+            status: TrayStatus::Active,
+            animation: None,
             info,
             windows_loop: Some(windows_loop),
             event_loop: Some(event_loop),
             event_tx,
         };
 
-        w.set_tooltip(title)?;
+        w.set_tip(title)?;
         w.set_icon(icon)?;
 
         Ok(w)
     }
 
-    pub fn set_icon(&self, icon: IconSource) -> Result<(), TIError> {
-        match icon {
-            IconSource::Resource(icon_str) => return self.set_icon_from_resource(icon_str),
-            IconSource::RawIcon(raw_icon) => self._set_icon(raw_icon),
-        }
+    // The only backend there is, whichever was asked for
+    pub fn with_backend(
+        title: &str,
+        icon: IconSource,
+        menu: SharedMenu,
+        _backend: Backend,
+    ) -> Result<Self, TIError> {
+        Self::new(title, icon, menu)
     }
 
-    pub fn add_label(&mut self, label: &str) -> Result<(), TIError> {
-        self.add_label_with_id(label)?;
-        Ok(())
+    pub fn backend(&self) -> Backend {
+        Backend::Windows
     }
 
-    pub fn add_label_with_id(&mut self, label: &str) -> Result<u32, TIError> {
-        let item_idx = padlock::mutex_lock(&self.entries, |entries| {
-            let len = entries.len();
-            entries.push(None);
-            len
-        }) as u32;
-
-        let mut st = to_wstring(label);
-        let mut item = unsafe { mem::zeroed::<MENUITEMINFOW>() };
-        item.cbSize = mem::size_of::<MENUITEMINFOW>() as u32;
-        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
-        item.fType = MFT_STRING;
-        item.fState = MFS_DISABLED | MFS_UNHILITE;
-        item.wID = item_idx;
-        item.dwTypeData = st.as_mut_ptr();
-        item.cch = (label.len() * 2) as u32;
-
-        unsafe {
-            if InsertMenuItemW(self.info.hmenu, item_idx, 1, &item) == 0 {
-                return Err(get_win_os_error("Error inserting menu item"));
-            }
-        }
-        Ok(item_idx)
+    pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.icon = self.load_icon(icon)?;
+        self.animation = None;
+        self.show_icon()
     }
 
-    pub fn set_label(&mut self, label: &str, id: u32) -> Result<(), TIError> {
-        let mut st = to_wstring(label);
-        let mut item = unsafe { mem::zeroed::<MENUITEMINFOW>() };
-        item.cbSize = mem::size_of::<MENUITEMINFOW>() as u32;
-        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
-        item.fType = MFT_STRING;
-        item.fState = MFS_DISABLED | MFS_UNHILITE;
-        item.wID = id;
-        item.dwTypeData = st.as_mut_ptr();
-        item.cch = (label.len() * 2) as u32;
+    pub fn set_animated_icon(
+        &mut self,
+        frames: Vec<IconSource>,
+        interval: Duration,
+    ) -> Result<(), TIError> {
+        let frames = frames
+            .into_iter()
+            .map(|frame| self.load_icon(frame))
+            .collect::<Result<Vec<_>, _>>()?;
+        unsafe { set_notify_icon(self.info.hwnd, frames[0].handle)? };
+
+        // The frames are destroyed when the thread ends, the shell keeps a copy
+        // of the one on display
+        let hwnd = self.info.hwnd;
+        let mut frame = 0;
+        self.animation = Some(Ticker::start(interval, move || {
+            frame = (frame + 1) % frames.len();
+            unsafe { set_notify_icon(hwnd, frames[frame].handle).ok() };
+        }));
 
-        unsafe {
-            if SetMenuItemInfoW(self.info.hmenu, id, 1, &item) == 0 {
-                return Err(get_win_os_error("Error inserting menu item"));
-            }
-        }
         Ok(())
-
     }
 
-    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<(), TIError>
-    where
-        F: Fn() + Send + 'static,
-    {
-        self.add_menu_item_with_id(label, cb)?;
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        if self.animation.take().is_some() {
+            self.show_icon()?;
+        }
+
         Ok(())
     }
 
-    pub fn add_menu_item_with_id<F>(&mut self, label: &str, cb: F) -> Result<u32, TIError>
-    where
-        F: Fn() + Send + 'static,
-    {
-        let item_idx = padlock::mutex_lock(&self.entries, |entries| {
-            let len = entries.len();
-            entries.push(Some(Box::new(cb)));
-            len
-        }) as u32;
-
-        let mut st = to_wstring(label);
-        let mut item = unsafe { mem::zeroed::<MENUITEMINFOW>() };
-        item.cbSize = mem::size_of::<MENUITEMINFOW>() as u32;
-        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
-        item.fType = MFT_STRING;
-        item.wID = item_idx;
-        item.dwTypeData = st.as_mut_ptr();
-        item.cch = (label.len() * 2) as u32;
+    pub fn set_title_label(&mut self, _label: Option<&str>) -> Result<(), TIError> {
+        Err(TIError::new("Labels are not supported on Windows"))
+    }
 
-        unsafe {
-            if InsertMenuItemW(self.info.hmenu, item_idx, 1, &item) == 0 {
-                return Err(get_win_os_error("Error inserting menu item"));
-            }
-        }
-        Ok(item_idx)
+    pub fn set_overlay_icon(&mut self, _icon: Option<IconSource>) -> Result<(), TIError> {
+        Err(TIError::new("Overlay icons are not supported on Windows"))
+    }
+
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.attention_icon = Some(self.load_icon(icon)?);
+        self.show_icon()
     }
 
-    pub fn set_menu_item_label(&mut self, label: &str, id: u32) -> Result<(), TIError> {
-        let mut st = to_wstring(label);
-        let mut item = unsafe { mem::zeroed::<MENUITEMINFOW>() };
-        item.cbSize = mem::size_of::<MENUITEMINFOW>() as u32;
-        item.fMask = MIIM_FTYPE | MIIM_STRING | MIIM_ID | MIIM_STATE;
-        item.fType = MFT_STRING;
-        item.wID = id;
-        item.dwTypeData = st.as_mut_ptr();
-        item.cch = (label.len() * 2) as u32;
+    pub fn set_attention_movie(&mut self, _name: &str) -> Result<(), TIError> {
+        Err(TIError::new(
+            "Attention movies are not supported on Windows",
+        ))
+    }
 
-        unsafe {
-            if SetMenuItemInfoW(self.info.hmenu, id, 1, &item) == 0 {
-                return Err(get_win_os_error("Error setting menu item"));
+    pub(crate) fn enable_icon_action(&mut self, action: IconAction) -> Result<(), TIError> {
+        match action {
+            // The menu stays available on right click
+            IconAction::Activate => self.left_click_menu.store(false, Ordering::Relaxed),
+            IconAction::SecondaryActivate => (),
+            IconAction::Scroll => {
+                return Err(TIError::new(
+                    "Scroll is not supported, the notification area does not report it",
+                ))
             }
         }
+
         Ok(())
     }
 
-    pub fn add_separator(&mut self) -> Result<(), TIError> {
-        self.add_separator_with_id()?;
-        Ok(())
+    pub fn update_menu(&mut self) -> Result<(), TIError> {
+        unsafe { render_menu(&self.info, &self.menu, &self.entries) }
     }
 
-    pub fn add_separator_with_id(&mut self) -> Result<u32, TIError> {
-        let item_idx = padlock::mutex_lock(&self.entries, |entries| {
-            let len = entries.len();
-            entries.push(None);
-            len
-        }) as u32;
+    pub fn set_icon_theme_path(&mut self, _path: &Path) -> Result<(), TIError> {
+        Err(TIError::new(
+            "Icon theme paths are not supported on Windows",
+        ))
+    }
 
-        let mut item = unsafe { mem::zeroed::<MENUITEMINFOW>() };
-        item.cbSize = mem::size_of::<MENUITEMINFOW>() as u32;
-        item.fMask = MIIM_FTYPE | MIIM_ID | MIIM_STATE;
-        item.fType = MFT_SEPARATOR;
-        item.wID = item_idx;
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.status = status;
+
+        // The notification area has no attention state, passive hides the icon and
+        // needing attention swaps in the attention icon
+        let mut nid = unsafe { mem::zeroed::<NOTIFYICONDATAW>() };
+        nid.cbSize = mem::size_of::<NOTIFYICONDATAW>() as u32;
+        nid.hWnd = self.info.hwnd;
+        nid.uID = 1;
+        nid.uFlags = NIF_STATE;
+        nid.dwStateMask = NIS_HIDDEN;
+        if status == TrayStatus::Passive {
+            nid.dwState = NIS_HIDDEN;
+        }
 
         unsafe {
-            if InsertMenuItemW(self.info.hmenu, item_idx, 1, &item) == 0 {
-                return Err(get_win_os_error("Error inserting menu separator"));
+            if Shell_NotifyIconW(NIM_MODIFY, &nid) == 0 {
+                return Err(get_win_os_error("Error setting status"));
             }
         }
-        Ok(item_idx)
+
+        self.show_icon()
     }
 
-    pub fn set_tooltip(&self, tooltip: &str) -> Result<(), TIError> {
-        let wide_tooltip = to_wstring(tooltip);
-        if wide_tooltip.len() > 128 {
-            return Err(TIError::new("The tooltip may not exceed 127 wide bytes"));
+    pub fn set_tooltip(&self, tooltip: Tooltip) -> Result<(), TIError> {
+        self.set_tip(&tooltip.text())
+    }
+
+    fn set_tip(&self, tooltip: &str) -> Result<(), TIError> {
+        // szTip holds 127 UTF-16 units and the terminator, longer tips are cut off
+        let mut wide_tooltip: Vec<u16> = tooltip.encode_utf16().take(127).collect();
+        if matches!(wide_tooltip.last(), Some(0xD800..=0xDBFF)) {
+            // Don't leave half of a surrogate pair behind
+            wide_tooltip.pop();
         }
+        wide_tooltip.push(0);
 
         let mut nid = unsafe { mem::zeroed::<NOTIFYICONDATAW>() };
         nid.cbSize = mem::size_of::<NOTIFYICONDATAW>() as u32;
@@ -276,7 +314,43 @@ impl TrayItemWindows {
         Ok(())
     }
 
-    fn set_icon_from_resource(&self, resource_name: &str) -> Result<(), TIError> {
+    fn load_icon(&self, icon: IconSource) -> Result<Icon, TIError> {
+        let handle = match icon {
+            IconSource::Resource(icon_str) => self.load_icon_from_resource(icon_str)?,
+            IconSource::RawIcon(raw_icon) => {
+                return Ok(Icon {
+                    handle: raw_icon,
+                    owned: false,
+                })
+            }
+            IconSource::Pixels {
+                width,
+                height,
+                format,
+                data,
+            } => unsafe {
+                create_icon(&Pixmap {
+                    width,
+                    height,
+                    format,
+                    data,
+                })?
+            },
+            IconSource::Pixmaps(pixmaps) => {
+                let size = unsafe { GetSystemMetrics(SM_CXSMICON) };
+                let pixmap = icon::closest(&pixmaps, size)
+                    .ok_or_else(|| TIError::new("The icon has no pixmaps"))?;
+                unsafe { create_icon(pixmap)? }
+            }
+        };
+
+        Ok(Icon {
+            handle,
+            owned: true,
+        })
+    }
+
+    fn load_icon_from_resource(&self, resource_name: &str) -> Result<HICON, TIError> {
         let icon = unsafe {
             let handle = LoadImageW(
                 self.info.hmodule,
@@ -294,23 +368,18 @@ impl TrayItemWindows {
             handle
         };
 
-        self._set_icon(icon)
+        Ok(icon)
     }
 
-    fn _set_icon(&self, icon: HICON) -> Result<(), TIError> {
-        let mut nid = unsafe { mem::zeroed::<NOTIFYICONDATAW>() };
-        nid.cbSize = mem::size_of::<NOTIFYICONDATAW>() as u32;
-        nid.hWnd = self.info.hwnd;
-        nid.uID = 1;
-        nid.uFlags = NIF_ICON;
-        nid.hIcon = icon;
-
-        unsafe {
-            if Shell_NotifyIconW(NIM_MODIFY, &nid) == 0 {
-                return Err(get_win_os_error("Error setting icon"));
-            }
+    fn show_icon(&self) -> Result<(), TIError> {
+        match (self.status, &self.attention_icon) {
+            (TrayStatus::NeedsAttention, Some(icon)) => self._set_icon(icon.handle),
+            _ => self._set_icon(self.icon.handle),
         }
-        Ok(())
+    }
+
+    fn _set_icon(&self, icon: HICON) -> Result<(), TIError> {
+        unsafe { set_notify_icon(self.info.hwnd, icon) }
     }
 
     pub fn quit(&mut self) {
@@ -323,7 +392,7 @@ impl TrayItemWindows {
         }
 
         if let Some(t) = self.event_loop.take() {
-            self.event_tx.send(WindowsTrayEvent(u32::MAX)).ok();
+            self.event_tx.send(WindowsTrayEvent::Quit).ok();
             t.join().ok();
         }
     }
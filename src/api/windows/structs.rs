@@ -1,10 +1,12 @@
-use std::sync::mpsc::Sender;
+use std::sync::{atomic::AtomicBool, mpsc::Sender, Arc};
 
 use windows_sys::Win32::{
     Foundation::{HMODULE, HWND},
-    UI::WindowsAndMessaging::HMENU,
+    UI::WindowsAndMessaging::{DestroyIcon, HICON, HMENU},
 };
 
+use crate::MenuItemId;
+
 #[derive(Clone)]
 pub(crate) struct WindowInfo {
     pub hwnd: HWND,
@@ -19,6 +21,35 @@ unsafe impl Sync for WindowInfo {}
 pub(crate) struct WindowsLoopData {
     pub info: WindowInfo,
     pub tx: Sender<WindowsTrayEvent>,
+    // Cleared once a handler for left clicks is registered
+    pub left_click_menu: Arc<AtomicBool>,
+}
+
+pub(crate) enum WindowsTrayEvent {
+    // wID of the clicked menu item
+    MenuItem(u32),
+    Activated(i32, i32),
+    SecondaryActivated(i32, i32),
+    Quit,
+}
+
+// An icon handle, destroyed on drop unless the user passed it in and owns it
+pub(crate) struct Icon {
+    pub handle: HICON,
+    pub owned: bool,
+}
+
+impl Drop for Icon {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { DestroyIcon(self.handle) };
+        }
+    }
 }
 
-pub(crate) struct WindowsTrayEvent(pub(crate) u32);
+// A rendered menu item, `option` is set for the entries of a radio group
+#[derive(Clone, Copy)]
+pub(crate) struct MenuEntry {
+    pub id: MenuItemId,
+    pub option: Option<usize>,
+}
@@ -0,0 +1,174 @@
+use {
+    crate::MenuItemId,
+    std::sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+#[cfg(feature = "async")]
+use {
+    futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    futures_core::Stream,
+    std::{
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+pub(crate) type SharedEvents = Arc<Events>;
+
+type PositionHandler = Arc<dyn Fn(i32, i32) + Send + Sync + 'static>;
+type ScrollHandler = Arc<dyn Fn(i32, ScrollOrientation) + Send + Sync + 'static>;
+
+// Icon interactions a handler can be registered for, backends report whether
+// they can deliver them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum IconAction {
+    Activate,
+    SecondaryActivate,
+    Scroll,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrollOrientation {
+    Horizontal,
+    Vertical,
+}
+
+impl ScrollOrientation {
+    // Hosts disagree on the case of the orientation
+    #[cfg(all(
+        target_os = "linux",
+        any(feature = "ksni", feature = "zbus"),
+        not(feature = "mock")
+    ))]
+    pub(crate) fn from_sni(orientation: &str) -> Self {
+        if orientation.eq_ignore_ascii_case("horizontal") {
+            Self::Horizontal
+        } else {
+            Self::Vertical
+        }
+    }
+}
+
+/// Everything the user did with the tray, see [`crate::TrayItem::events`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrayEvent {
+    MenuItemActivated(MenuItemId),
+    CheckToggled(MenuItemId, bool),
+    RadioSelected(MenuItemId, usize),
+    /// Primary click on the icon, at screen coordinates when the platform reports them.
+    IconActivated {
+        x: i32,
+        y: i32,
+    },
+    /// Usually a middle click on the icon.
+    SecondaryActivated {
+        x: i32,
+        y: i32,
+    },
+    Scrolled {
+        delta: i32,
+        orientation: ScrollOrientation,
+    },
+}
+
+/// Async counterpart of [`crate::TrayItem::events`], returned by
+/// [`crate::TrayItem::event_stream`].
+#[cfg(feature = "async")]
+pub struct EventStream(UnboundedReceiver<TrayEvent>);
+
+#[cfg(feature = "async")]
+impl Stream for EventStream {
+    type Item = TrayEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<TrayEvent>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+#[derive(Clone, Default)]
+struct Handlers {
+    activate: Option<PositionHandler>,
+    secondary_activate: Option<PositionHandler>,
+    scroll: Option<ScrollHandler>,
+}
+
+// Runs the icon handlers and fans events out to every receiver handed out by
+// `subscribe`
+#[derive(Default)]
+pub(crate) struct Events {
+    handlers: Mutex<Handlers>,
+    senders: Mutex<Vec<Sender<TrayEvent>>>,
+    #[cfg(feature = "async")]
+    streams: Mutex<Vec<UnboundedSender<TrayEvent>>>,
+}
+
+impl Events {
+    pub(crate) fn subscribe(&self) -> Receiver<TrayEvent> {
+        let (tx, rx) = channel();
+        self.senders.lock().unwrap().push(tx);
+
+        rx
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn subscribe_stream(&self) -> EventStream {
+        let (tx, rx) = unbounded();
+        self.streams.lock().unwrap().push(tx);
+
+        EventStream(rx)
+    }
+
+    pub(crate) fn set_activate(&self, handler: PositionHandler) {
+        self.handlers.lock().unwrap().activate = Some(handler);
+    }
+
+    pub(crate) fn set_secondary_activate(&self, handler: PositionHandler) {
+        self.handlers.lock().unwrap().secondary_activate = Some(handler);
+    }
+
+    pub(crate) fn set_scroll(&self, handler: ScrollHandler) {
+        self.handlers.lock().unwrap().scroll = Some(handler);
+    }
+
+    pub(crate) fn send(&self, event: TrayEvent) {
+        self.run_handler(event);
+
+        // Receivers that were dropped are forgotten
+        self.senders
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event).is_ok());
+
+        #[cfg(feature = "async")]
+        self.streams
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event).is_ok());
+    }
+
+    fn run_handler(&self, event: TrayEvent) {
+        // Work on a copy so a handler is free to register new handlers
+        let handlers = self.handlers.lock().unwrap().clone();
+        match event {
+            TrayEvent::IconActivated { x, y } => {
+                if let Some(handler) = handlers.activate {
+                    handler(x, y);
+                }
+            }
+            TrayEvent::SecondaryActivated { x, y } => {
+                if let Some(handler) = handlers.secondary_activate {
+                    handler(x, y);
+                }
+            }
+            TrayEvent::Scrolled { delta, orientation } => {
+                if let Some(handler) = handlers.scroll {
+                    handler(delta, orientation);
+                }
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,490 @@
+// Without any backend nothing reads the pixels
+#![cfg_attr(
+    all(
+        target_os = "linux",
+        not(any(
+            feature = "ksni",
+            feature = "zbus",
+            feature = "libappindicator",
+            feature = "xembed",
+            feature = "mock"
+        ))
+    ),
+    allow(dead_code)
+)]
+
+use crate::TIError;
+
+#[cfg(any(feature = "png", feature = "ico", feature = "svg"))]
+use {
+    crate::IconSource,
+    std::{fs, path::Path},
+};
+
+/// Byte order of the pixels in [`crate::IconSource::Pixels`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// `R, G, B, A`, what most image decoders produce.
+    Rgba8,
+    /// `A, R, G, B`, the format StatusNotifierItem uses on the wire.
+    Argb32,
+    /// `B, G, R, A`, a little endian ARGB32 word.
+    Bgra8,
+}
+
+/// One size of a multi-resolution [`crate::IconSource::Pixmaps`] icon.
+#[derive(Clone)]
+pub struct Pixmap {
+    pub width: i32,
+    pub height: i32,
+    pub format: PixelFormat,
+    pub data: Vec<u8>,
+}
+
+impl Pixmap {
+    // The pixels in `format`, checking the data holds exactly `width * height` of them
+    pub(crate) fn convert(&self, format: PixelFormat) -> Result<Vec<u8>, TIError> {
+        convert(self.width, self.height, self.format, format, &self.data)
+    }
+}
+
+/// The pixmap closest to `size`, preferring to scale down over scaling up.
+#[cfg(any(
+    test,
+    all(
+        any(
+            target_os = "windows",
+            target_os = "macos",
+            all(
+                target_os = "linux",
+                any(feature = "libappindicator", feature = "xembed")
+            )
+        ),
+        not(feature = "mock")
+    )
+))]
+pub(crate) fn closest(pixmaps: &[Pixmap], size: i32) -> Option<&Pixmap> {
+    pixmaps
+        .iter()
+        .min_by_key(|pixmap| match pixmap.width.max(pixmap.height) - size {
+            larger if larger >= 0 => (0, larger),
+            smaller => (1, -smaller),
+        })
+}
+
+/// `(width, height, ARGB32 data)` per size, as StatusNotifierItem sends them.
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus"),
+    not(feature = "mock")
+))]
+pub(crate) type SniPixmaps = Vec<(i32, i32, Vec<u8>)>;
+
+// An icon converted to what SNI expects, so property reads don't redo the work
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus"),
+    not(feature = "mock")
+))]
+#[derive(Clone, Default)]
+pub(crate) struct SniIcon {
+    pub(crate) name: String,
+    pub(crate) pixmaps: SniPixmaps,
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus"),
+    not(feature = "mock")
+))]
+impl SniIcon {
+    /// Name and ARGB32 pixmaps of `icon`, the way StatusNotifierItem exports it.
+    pub(crate) fn new(icon: &crate::IconSource) -> Result<Self, TIError> {
+        use crate::IconSource;
+
+        let pixmap = |width, height, format, data| {
+            Ok((
+                width,
+                height,
+                convert(width, height, format, PixelFormat::Argb32, data)?,
+            ))
+        };
+
+        let (name, pixmaps) = match icon {
+            IconSource::Resource(name) => (name.to_string(), vec![]),
+            IconSource::Data {
+                height,
+                width,
+                data,
+            } => (
+                String::new(),
+                vec![pixmap(*width, *height, PixelFormat::Argb32, data)?],
+            ),
+            IconSource::Pixels {
+                width,
+                height,
+                format,
+                data,
+            } => (String::new(), vec![pixmap(*width, *height, *format, data)?]),
+            IconSource::Pixmaps(pixmaps) => (
+                String::new(),
+                pixmaps
+                    .iter()
+                    .map(|p| Ok((p.width, p.height, p.convert(PixelFormat::Argb32)?)))
+                    .collect::<Result<_, TIError>>()?,
+            ),
+        };
+
+        Ok(Self { name, pixmaps })
+    }
+}
+
+pub(crate) fn convert(
+    width: i32,
+    height: i32,
+    from: PixelFormat,
+    to: PixelFormat,
+    data: &[u8],
+) -> Result<Vec<u8>, TIError> {
+    let expected = usize::try_from(width)
+        .ok()
+        .zip(usize::try_from(height).ok())
+        .filter(|&(width, height)| width > 0 && height > 0)
+        .and_then(|(width, height)| width.checked_mul(height)?.checked_mul(4))
+        .ok_or_else(|| TIError::new(format!("Invalid icon size {}x{}", width, height)))?;
+    if data.len() != expected {
+        return Err(TIError::new(format!(
+            "Icon data is {} bytes, a {}x{} icon needs {}",
+            data.len(),
+            width,
+            height,
+            expected
+        )));
+    }
+
+    // Every format is turned into ARGB32 first and from there into the target
+    let mut pixels = data.to_vec();
+    for pixel in pixels.chunks_exact_mut(4) {
+        match from {
+            PixelFormat::Rgba8 => pixel.rotate_right(1),
+            PixelFormat::Argb32 => (),
+            PixelFormat::Bgra8 => pixel.reverse(),
+        }
+        match to {
+            PixelFormat::Rgba8 => pixel.rotate_left(1),
+            PixelFormat::Argb32 => (),
+            PixelFormat::Bgra8 => pixel.reverse(),
+        }
+    }
+
+    Ok(pixels)
+}
+
+#[cfg(any(feature = "png", feature = "ico", feature = "svg"))]
+impl IconSource {
+    /// Loads an icon file, picking the decoder from the extension. SVGs are
+    /// rendered at the size they declare.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, TIError> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)
+            .map_err(|e| TIError::new(format!("Error reading icon {}: {}", path.display(), e)))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            #[cfg(feature = "png")]
+            Some(ext) if ext.eq_ignore_ascii_case("png") => Self::from_png_bytes(&bytes),
+            #[cfg(feature = "ico")]
+            Some(ext) if ext.eq_ignore_ascii_case("ico") => Self::from_ico_bytes(&bytes),
+            #[cfg(feature = "svg")]
+            Some(ext) if ext.eq_ignore_ascii_case("svg") => {
+                let size = svg_tree(&bytes)?.size();
+                Self::from_svg_bytes(&bytes, size.width().max(size.height()).ceil() as u32)
+            }
+            _ => Err(TIError::new(format!(
+                "Unsupported icon file {}",
+                path.display()
+            ))),
+        }
+    }
+
+    #[cfg(feature = "png")]
+    pub fn from_png_bytes(bytes: &[u8]) -> Result<Self, TIError> {
+        let error = |e| TIError::new(format!("Error decoding PNG: {}", e));
+
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().map_err(error)?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).map_err(error)?;
+        buf.truncate(info.buffer_size());
+
+        // Indexed images are already expanded, the rest only lack channels
+        let data = match info.color_type {
+            png::ColorType::Rgba => buf,
+            png::ColorType::Rgb => buf
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 0xff])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buf
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            png::ColorType::Grayscale => buf.iter().flat_map(|&v| [v, v, v, 0xff]).collect(),
+            png::ColorType::Indexed => {
+                return Err(TIError::new("Error decoding PNG: unexpanded palette"))
+            }
+        };
+
+        Ok(IconSource::Pixels {
+            width: info.width as i32,
+            height: info.height as i32,
+            format: PixelFormat::Rgba8,
+            data,
+        })
+    }
+
+    /// Every size in the file, see [`IconSource::Pixmaps`].
+    #[cfg(feature = "ico")]
+    pub fn from_ico_bytes(bytes: &[u8]) -> Result<Self, TIError> {
+        let error = |e| TIError::new(format!("Error decoding ICO: {}", e));
+
+        let dir = ico::IconDir::read(std::io::Cursor::new(bytes)).map_err(error)?;
+        let pixmaps = dir
+            .entries()
+            .iter()
+            .map(|entry| {
+                let image = entry.decode().map_err(error)?;
+                Ok(Pixmap {
+                    width: image.width() as i32,
+                    height: image.height() as i32,
+                    format: PixelFormat::Rgba8,
+                    data: image.rgba_data().to_vec(),
+                })
+            })
+            .collect::<Result<Vec<_>, TIError>>()?;
+        if pixmaps.is_empty() {
+            return Err(TIError::new("Error decoding ICO: no images"));
+        }
+
+        Ok(IconSource::Pixmaps(pixmaps))
+    }
+
+    /// Renders the SVG into a `size` x `size` square, keeping its aspect ratio.
+    #[cfg(feature = "svg")]
+    pub fn from_svg_bytes(bytes: &[u8], size: u32) -> Result<Self, TIError> {
+        use resvg::tiny_skia;
+
+        let tree = svg_tree(bytes)?;
+        let mut pixmap = tiny_skia::Pixmap::new(size, size)
+            .ok_or_else(|| TIError::new(format!("Invalid icon size {}x{}", size, size)))?;
+
+        let svg = tree.size();
+        let scale = size as f32 / svg.width().max(svg.height());
+        let transform = tiny_skia::Transform::from_scale(scale, scale).post_translate(
+            (size as f32 - svg.width() * scale) / 2.0,
+            (size as f32 - svg.height() * scale) / 2.0,
+        );
+        resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+        // tiny-skia works with premultiplied alpha
+        let data = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|p| {
+                let c = p.demultiply();
+                [c.red(), c.green(), c.blue(), c.alpha()]
+            })
+            .collect();
+
+        Ok(IconSource::Pixels {
+            width: size as i32,
+            height: size as i32,
+            format: PixelFormat::Rgba8,
+            data,
+        })
+    }
+}
+
+#[cfg(feature = "svg")]
+fn svg_tree(bytes: &[u8]) -> Result<resvg::usvg::Tree, TIError> {
+    resvg::usvg::Tree::from_data(bytes, &resvg::usvg::Options::default())
+        .map_err(|e| TIError::new(format!("Error decoding SVG: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One opaque red pixel and one half transparent blue one
+    const RGBA: [u8; 8] = [0xff, 0, 0, 0xff, 0, 0, 0xff, 0x80];
+    const ARGB: [u8; 8] = [0xff, 0xff, 0, 0, 0x80, 0, 0, 0xff];
+    const BGRA: [u8; 8] = [0, 0, 0xff, 0xff, 0xff, 0, 0, 0x80];
+
+    #[test]
+    fn pixels_convert_between_formats() {
+        let formats = [
+            (PixelFormat::Rgba8, RGBA),
+            (PixelFormat::Argb32, ARGB),
+            (PixelFormat::Bgra8, BGRA),
+        ];
+
+        for (from, data) in formats {
+            for (to, expected) in formats {
+                assert_eq!(convert(2, 1, from, to, &data).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn pixmaps_convert_their_data() {
+        let pixmap = Pixmap {
+            width: 1,
+            height: 2,
+            format: PixelFormat::Bgra8,
+            data: BGRA.to_vec(),
+        };
+
+        assert_eq!(pixmap.convert(PixelFormat::Argb32).unwrap(), ARGB);
+    }
+
+    #[test]
+    fn data_must_fit_the_size() {
+        let to = PixelFormat::Argb32;
+
+        assert!(convert(2, 2, PixelFormat::Rgba8, to, &RGBA).is_err());
+        assert!(convert(1, 1, PixelFormat::Rgba8, to, &RGBA).is_err());
+        assert!(convert(2, 1, PixelFormat::Rgba8, to, &RGBA[..7]).is_err());
+    }
+
+    #[test]
+    fn sizes_must_be_positive() {
+        let to = PixelFormat::Argb32;
+
+        assert!(convert(0, 0, PixelFormat::Rgba8, to, &[]).is_err());
+        assert!(convert(0, 2, PixelFormat::Rgba8, to, &[]).is_err());
+        assert!(convert(-2, -1, PixelFormat::Rgba8, to, &RGBA).is_err());
+        assert!(convert(-1, 2, PixelFormat::Rgba8, to, &RGBA).is_err());
+        assert!(convert(i32::MAX, i32::MAX, PixelFormat::Rgba8, to, &RGBA).is_err());
+    }
+
+    fn sizes(sizes: &[(i32, i32)]) -> Vec<Pixmap> {
+        sizes
+            .iter()
+            .map(|&(width, height)| Pixmap {
+                width,
+                height,
+                format: PixelFormat::Rgba8,
+                data: vec![0; (width * height * 4) as usize],
+            })
+            .collect()
+    }
+
+    fn closest_size(pixmaps: &[Pixmap], size: i32) -> Option<(i32, i32)> {
+        closest(pixmaps, size).map(|pixmap| (pixmap.width, pixmap.height))
+    }
+
+    #[test]
+    fn closest_prefers_scaling_down() {
+        let pixmaps = sizes(&[(16, 16), (32, 32), (48, 48)]);
+
+        assert_eq!(closest_size(&pixmaps, 32), Some((32, 32)));
+        // A tie goes to the larger one, and so does a smaller one that is nearer
+        assert_eq!(closest_size(&pixmaps, 24), Some((32, 32)));
+        assert_eq!(closest_size(&pixmaps, 20), Some((32, 32)));
+        assert_eq!(closest_size(&pixmaps, 40), Some((48, 48)));
+        assert_eq!(closest_size(&pixmaps, 8), Some((16, 16)));
+    }
+
+    #[test]
+    fn closest_scales_up_the_largest() {
+        let pixmaps = sizes(&[(22, 22), (16, 16)]);
+
+        assert_eq!(closest_size(&pixmaps, 64), Some((22, 22)));
+        assert_eq!(closest_size(&[], 64), None);
+    }
+
+    #[test]
+    fn closest_goes_by_the_longer_side() {
+        let pixmaps = sizes(&[(32, 16), (16, 24), (24, 24)]);
+
+        assert_eq!(closest_size(&pixmaps, 24), Some((16, 24)));
+        assert_eq!(closest_size(&pixmaps, 30), Some((32, 16)));
+    }
+
+    #[cfg(any(feature = "png", feature = "svg"))]
+    fn pixels(icon: IconSource) -> (i32, i32, Vec<u8>) {
+        match icon {
+            IconSource::Pixels {
+                width,
+                height,
+                format,
+                data,
+            } => (
+                width,
+                height,
+                convert(width, height, format, PixelFormat::Rgba8, &data).unwrap(),
+            ),
+            _ => panic!("Expected pixels"),
+        }
+    }
+
+    // `RGBA` as a 2x1 PNG
+    #[cfg(feature = "png")]
+    const PNG: [u8; 71] = [
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44,
+        0x52, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0xf4,
+        0x22, 0x7f, 0x8a, 0x00, 0x00, 0x00, 0x0e, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0xf8,
+        0xcf, 0xc0, 0x00, 0x42, 0x0d, 0x00, 0x0f, 0x7a, 0x03, 0x7e, 0x6a, 0x81, 0x31, 0xe1, 0x00,
+        0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn png_is_decoded() {
+        let icon = IconSource::from_png_bytes(&PNG).unwrap();
+
+        assert_eq!(pixels(icon), (2, 1, RGBA.to_vec()));
+        assert!(IconSource::from_png_bytes(&PNG[..40]).is_err());
+    }
+
+    #[cfg(feature = "ico")]
+    #[test]
+    fn ico_keeps_every_size() {
+        let mut dir = ico::IconDir::new(ico::ResourceType::Icon);
+        for size in [1, 2] {
+            let rgba = RGBA[..4].repeat(size * size);
+            let image = ico::IconImage::from_rgba_data(size as u32, size as u32, rgba);
+            dir.add_entry(ico::IconDirEntry::encode(&image).unwrap());
+        }
+        let mut bytes = Vec::new();
+        dir.write(&mut bytes).unwrap();
+
+        let IconSource::Pixmaps(pixmaps) = IconSource::from_ico_bytes(&bytes).unwrap() else {
+            panic!("Expected pixmaps");
+        };
+        let sizes: Vec<_> = pixmaps.iter().map(|p| (p.width, p.height)).collect();
+        assert_eq!(sizes, [(1, 1), (2, 2)]);
+        assert_eq!(
+            pixmaps[1].convert(PixelFormat::Rgba8).unwrap(),
+            RGBA[..4].repeat(4)
+        );
+        assert!(IconSource::from_ico_bytes(&bytes[..10]).is_err());
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn svg_is_rendered_centered() {
+        let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2">
+            <rect width="4" height="2" fill="#ff0000"/>
+        </svg>"##;
+
+        let (width, height, data) = pixels(IconSource::from_svg_bytes(svg, 4).unwrap());
+        assert_eq!((width, height), (4, 4));
+        // The wide image is letterboxed, the rows above and below stay clear
+        let rows: Vec<_> = data.chunks_exact(16).collect();
+        assert_eq!(rows[0], [0; 16]);
+        assert_eq!(rows[1], RGBA[..4].repeat(4));
+        assert_eq!(rows[2], RGBA[..4].repeat(4));
+        assert_eq!(rows[3], [0; 16]);
+        assert!(IconSource::from_svg_bytes(b"<svg", 4).is_err());
+    }
+}
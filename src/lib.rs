@@ -1,53 +1,616 @@
 mod api;
 mod error;
+mod event;
+mod icon;
+mod menu;
 pub use error::TIError;
+#[cfg(feature = "async")]
+pub use event::EventStream;
+pub use event::{ScrollOrientation, TrayEvent};
+pub use icon::{PixelFormat, Pixmap};
+pub use menu::{Menu, MenuItem, MenuItemBuilder, MenuItemId, MenuItemKind};
 
-pub struct TrayItem(api::TrayItemImpl);
+use {
+    event::IconAction,
+    menu::SharedMenu,
+    std::{
+        path::Path,
+        sync::{mpsc::Receiver, Arc, Mutex},
+        time::Duration,
+    },
+};
+
+// Shorter intervals would only keep a thread spinning, no host redraws that fast
+const MIN_FRAME_INTERVAL: Duration = Duration::from_millis(10);
+
+pub struct TrayItem {
+    inner: api::TrayItemImpl,
+    menu: SharedMenu,
+}
 
 #[derive(Clone)]
 pub enum IconSource {
     Resource(&'static str),
     #[cfg(target_os = "windows")]
     RawIcon(windows_sys::Win32::UI::WindowsAndMessaging::HICON),
-    #[cfg(any(target_os = "macos", all(target_os = "linux", feature = "ksni")))]
+    /// Encoded image on macOS, ARGB32 pixels in network byte order on Linux.
+    #[cfg(any(
+        target_os = "macos",
+        all(
+            target_os = "linux",
+            any(feature = "ksni", feature = "zbus", feature = "xembed")
+        )
+    ))]
     Data {
         height: i32,
         width: i32,
         data: Vec<u8>,
     },
+    /// Raw pixels, converted by the crate to what the backend needs.
+    Pixels {
+        width: i32,
+        height: i32,
+        format: PixelFormat,
+        data: Vec<u8>,
+    },
+    /// The same icon in several sizes. ksni and zbus send all of them so the host can
+    /// pick, the other backends use the one closest to the size they draw at.
+    Pixmaps(Vec<Pixmap>),
 }
 
 impl IconSource {
+    /// Name of an [`IconSource::Resource`] icon, empty for the other kinds.
     pub fn as_str(&self) -> &str {
         match self {
             IconSource::Resource(res) => res,
-            #[allow(unreachable_patterns)]
-            _ => unimplemented!(),
+            _ => "",
+        }
+    }
+}
+
+/// How prominently the host should show the icon.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrayStatus {
+    #[default]
+    Active,
+    /// Idle, hosts may tuck the icon away or hide it.
+    Passive,
+    NeedsAttention,
+}
+
+/// The implementation showing the tray, see [`TrayItem::with_backend`]. Which
+/// variants exist depends on the platform and the enabled features.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backend {
+    #[cfg(all(target_os = "linux", feature = "ksni"))]
+    Ksni,
+    /// StatusNotifierItem served by the crate itself, without C libraries.
+    #[cfg(all(target_os = "linux", feature = "zbus"))]
+    Zbus,
+    #[cfg(all(target_os = "linux", feature = "libappindicator"))]
+    LibAppIndicator,
+    /// A window docked into the system tray of X11 desktops without an SNI host.
+    #[cfg(all(target_os = "linux", feature = "xembed"))]
+    XEmbed,
+    #[cfg(target_os = "windows")]
+    Windows,
+    #[cfg(target_os = "macos")]
+    MacOS,
+    /// Stands in for every other backend while the `mock` feature is enabled.
+    #[cfg(feature = "mock")]
+    Mock,
+}
+
+/// Text shown when hovering the icon.
+///
+/// Only the ksni and zbus backends show the description and icon separately, the other
+/// backends fold the description into the text or leave it out.
+#[derive(Clone, Default)]
+pub struct Tooltip {
+    pub title: String,
+    pub description: String,
+    pub icon: Option<IconSource>,
+}
+
+impl Tooltip {
+    // Plain text form for backends without a separate description
+    #[cfg(all(any(target_os = "windows", target_os = "macos"), not(feature = "mock")))]
+    pub(crate) fn text(&self) -> String {
+        if self.description.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{}\n{}", self.title, self.description)
+        }
+    }
+}
+
+impl From<&str> for Tooltip {
+    fn from(title: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            ..Default::default()
         }
     }
 }
 
 impl TrayItem {
+    /// Creates the tray with the best backend available. On Linux that is ksni or
+    /// zbus while a StatusNotifierWatcher runs on the session bus, libappindicator
+    /// or XEmbed otherwise, falling back to the next enabled one when a backend
+    /// fails to start.
     pub fn new(title: &str, icon: IconSource) -> Result<Self, TIError> {
-        Ok(Self(api::TrayItemImpl::new(title, icon)?))
+        let menu = Arc::new(Mutex::new(Menu::new()));
+
+        Ok(Self {
+            inner: api::TrayItemImpl::new(title, icon, Arc::clone(&menu))?,
+            menu,
+        })
+    }
+
+    /// Creates the tray with `backend`, without probing or falling back.
+    pub fn with_backend(title: &str, icon: IconSource, backend: Backend) -> Result<Self, TIError> {
+        let menu = Arc::new(Mutex::new(Menu::new()));
+
+        Ok(Self {
+            inner: api::TrayItemImpl::with_backend(title, icon, Arc::clone(&menu), backend)?,
+            menu,
+        })
     }
 
+    /// The backend showing this tray.
+    pub fn backend(&self) -> Backend {
+        self.inner.backend()
+    }
+
+    /// Replaces the icon, stopping any animation.
     pub fn set_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
-        self.0.set_icon(icon)
+        self.inner.set_icon(icon)
     }
 
-    pub fn add_label(&mut self, label: &str) -> Result<(), TIError> {
-        self.0.add_label(label)
+    /// Cycles through `frames` every `interval` until [`TrayItem::stop_animation`]
+    /// or [`TrayItem::set_icon`]. The frames are converted once, up front.
+    /// Intervals under 10 ms are rejected, under 100 ms on ksni.
+    pub fn set_animated_icon(
+        &mut self,
+        frames: Vec<IconSource>,
+        interval: Duration,
+    ) -> Result<(), TIError> {
+        if frames.is_empty() {
+            return Err(TIError::new("An animated icon needs at least one frame"));
+        }
+        if interval < MIN_FRAME_INTERVAL {
+            return Err(TIError::new(format!(
+                "Frame interval {:?} is too short, the minimum is {:?}",
+                interval, MIN_FRAME_INTERVAL
+            )));
+        }
+
+        self.inner.set_animated_icon(frames, interval)
     }
 
-    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<(), TIError>
+    /// Goes back to the icon set with [`TrayItem::set_icon`].
+    pub fn stop_animation(&mut self) -> Result<(), TIError> {
+        self.inner.stop_animation()
+    }
+
+    /// Short text shown beside the icon, such as a temperature or a count.
+    /// SNI has no label, so it replaces the title on ksni and zbus. `None` removes it.
+    pub fn set_title_label(&mut self, label: Option<&str>) -> Result<(), TIError> {
+        self.inner.set_title_label(label)
+    }
+
+    /// Directory searched for [`IconSource::Resource`] names before the system
+    /// theme, laid out like `hicolor` (`<size>/apps/<name>.png`).
+    pub fn set_icon_theme_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), TIError> {
+        self.inner.set_icon_theme_path(path.as_ref())
+    }
+
+    pub fn set_status(&mut self, status: TrayStatus) -> Result<(), TIError> {
+        self.inner.set_status(status)
+    }
+
+    /// Badge drawn over the icon, `None` removes it. Only supported by the ksni and zbus backends.
+    pub fn set_overlay_icon(&mut self, icon: Option<IconSource>) -> Result<(), TIError> {
+        self.inner.set_overlay_icon(icon)
+    }
+
+    /// Icon shown instead of the normal one while the status is
+    /// [`TrayStatus::NeedsAttention`].
+    pub fn set_attention_icon(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.inner.set_attention_icon(icon)
+    }
+
+    /// Named animation played while the status is [`TrayStatus::NeedsAttention`],
+    /// only supported by the ksni and zbus backends.
+    pub fn set_attention_movie(&mut self, name: &str) -> Result<(), TIError> {
+        self.inner.set_attention_movie(name)
+    }
+
+    pub fn set_tooltip<T: Into<Tooltip>>(&mut self, tooltip: T) -> Result<(), TIError> {
+        self.inner.set_tooltip(tooltip.into())
+    }
+
+    pub fn add_label(&mut self, label: &str) -> Result<MenuItemId, TIError> {
+        self.update_menu(|menu| Ok(menu.add_label(label)))
+    }
+
+    /// Add an item that only reports clicks through [`TrayItem::events`].
+    pub fn add_item(&mut self, label: &str) -> Result<MenuItemId, TIError> {
+        self.update_menu(|menu| Ok(menu.add_item(label)))
+    }
+
+    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<MenuItemId, TIError>
     where
         F: Fn() + Send + Sync + 'static,
     {
-        self.0.add_menu_item(label, cb)
+        self.update_menu(|menu| Ok(menu.add_menu_item(label, cb)))
+    }
+
+    /// Add a checkable item, `cb` receives the new state every time it is toggled.
+    pub fn add_check_item<F>(
+        &mut self,
+        label: &str,
+        checked: bool,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn(bool) + Send + Sync + 'static,
+    {
+        self.update_menu(|menu| Ok(menu.add_check_item(label, checked, cb)))
+    }
+
+    /// Add a group of mutually exclusive options, `cb` receives the index of the
+    /// option the user picked.
+    pub fn add_radio_group<F>(
+        &mut self,
+        options: &[&str],
+        selected: usize,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.update_menu(|menu| menu.add_radio_group(options, selected, cb))
+    }
+
+    pub fn add_separator(&mut self) -> Result<MenuItemId, TIError> {
+        self.update_menu(|menu| Ok(menu.add_separator()))
+    }
+
+    pub fn add_submenu(&mut self, label: &str) -> Result<SubmenuHandle<'_>, TIError> {
+        let id = self.update_menu(|menu| Ok(menu.add_submenu(label)))?;
+        Ok(SubmenuHandle { tray: self, id })
+    }
+
+    /// Get a handle to a submenu created earlier with [`TrayItem::add_submenu`].
+    pub fn submenu(&mut self, id: MenuItemId) -> Result<SubmenuHandle<'_>, TIError> {
+        let menu = self.menu.lock().unwrap();
+        menu.owns(id)?;
+        match menu.get(id).map(|item| item.kind()) {
+            Some(MenuItemKind::Submenu) => (),
+            _ => return Err(TIError::new(format!("Menu item {} is not a submenu", id))),
+        }
+        drop(menu);
+
+        Ok(SubmenuHandle { tray: self, id })
+    }
+
+    /// Insert an item at `index` of the menu, existing ids are not affected.
+    pub fn insert_item_at(
+        &mut self,
+        index: usize,
+        item: MenuItemBuilder,
+    ) -> Result<MenuItemId, TIError> {
+        self.update_menu(|menu| menu.insert_item_at(index, item))
+    }
+
+    /// Remove an item, or a submenu with all of its items.
+    pub fn remove_item(&mut self, id: MenuItemId) -> Result<(), TIError> {
+        self.update_menu(|menu| menu.remove(id).map(|_| ()))
+    }
+
+    /// Move an item to `index` within the menu or submenu it belongs to.
+    pub fn move_item(&mut self, id: MenuItemId, index: usize) -> Result<(), TIError> {
+        self.update_menu(|menu| menu.move_item(id, index))
+    }
+
+    pub fn clear_menu(&mut self) -> Result<(), TIError> {
+        self.update_menu(|menu| {
+            menu.clear();
+            Ok(())
+        })
+    }
+
+    pub fn set_menu_item_label(&mut self, label: &str, id: MenuItemId) -> Result<(), TIError> {
+        self.update_menu(|menu| menu.set_label(id, label))
+    }
+
+    pub fn set_enabled(&mut self, id: MenuItemId, enabled: bool) -> Result<(), TIError> {
+        self.update_menu(|menu| menu.set_enabled(id, enabled))
+    }
+
+    pub fn set_visible(&mut self, id: MenuItemId, visible: bool) -> Result<(), TIError> {
+        self.update_menu(|menu| menu.set_visible(id, visible))
+    }
+
+    pub fn set_checked(&mut self, id: MenuItemId, checked: bool) -> Result<(), TIError> {
+        self.update_menu(|menu| menu.set_checked(id, checked))
+    }
+
+    pub fn is_checked(&self, id: MenuItemId) -> Result<bool, TIError> {
+        self.menu.lock().unwrap().is_checked(id)
+    }
+
+    pub fn set_selected(&mut self, id: MenuItemId, index: usize) -> Result<(), TIError> {
+        self.update_menu(|menu| menu.set_selected(id, index))
+    }
+
+    pub fn selected(&self, id: MenuItemId) -> Result<usize, TIError> {
+        self.menu.lock().unwrap().selected(id)
+    }
+
+    /// Run `cb` with the cursor position when the icon is clicked. On Windows
+    /// and XEmbed this replaces opening the menu with a left click.
+    pub fn on_activate<F>(&mut self, cb: F) -> Result<(), TIError>
+    where
+        F: Fn(i32, i32) + Send + Sync + 'static,
+    {
+        self.inner.enable_icon_action(IconAction::Activate)?;
+        self.menu
+            .lock()
+            .unwrap()
+            .events()
+            .set_activate(Arc::new(cb));
+        Ok(())
+    }
+
+    /// Run `cb` with the cursor position when the icon is middle clicked.
+    pub fn on_secondary_activate<F>(&mut self, cb: F) -> Result<(), TIError>
+    where
+        F: Fn(i32, i32) + Send + Sync + 'static,
+    {
+        self.inner
+            .enable_icon_action(IconAction::SecondaryActivate)?;
+        self.menu
+            .lock()
+            .unwrap()
+            .events()
+            .set_secondary_activate(Arc::new(cb));
+        Ok(())
+    }
+
+    pub fn on_scroll<F>(&mut self, cb: F) -> Result<(), TIError>
+    where
+        F: Fn(i32, ScrollOrientation) + Send + Sync + 'static,
+    {
+        self.inner.enable_icon_action(IconAction::Scroll)?;
+        self.menu.lock().unwrap().events().set_scroll(Arc::new(cb));
+        Ok(())
+    }
+
+    /// Receive every [`TrayEvent`] of this tray, in addition to the callbacks.
+    /// Each call returns a new receiver that sees all events from then on.
+    pub fn events(&self) -> Receiver<TrayEvent> {
+        self.menu.lock().unwrap().events().subscribe()
+    }
+
+    /// Same as [`TrayItem::events`] as a `futures::Stream`, works with any executor.
+    #[cfg(feature = "async")]
+    pub fn event_stream(&self) -> EventStream {
+        self.menu.lock().unwrap().events().subscribe_stream()
+    }
+
+    /// Snapshot of the menu model currently rendered by the backend.
+    /// The copy is detached, its ids and callbacks do nothing on this tray.
+    pub fn menu(&self) -> Menu {
+        self.menu.lock().unwrap().clone()
     }
 
     pub fn inner_mut(&mut self) -> &mut api::TrayItemImpl {
-        &mut self.0
+        &mut self.inner
+    }
+
+    fn update_menu<R, F>(&mut self, f: F) -> Result<R, TIError>
+    where
+        F: FnOnce(&mut Menu) -> Result<R, TIError>,
+    {
+        let ret = f(&mut self.menu.lock().unwrap())?;
+        self.inner.update_menu()?;
+        Ok(ret)
+    }
+}
+
+// The ksni backend applies changes on its own thread and zbus awaits the signal
+// on the caller's executor, so these never block it on the D-Bus service.
+#[cfg(all(
+    feature = "async",
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus")
+))]
+impl TrayItem {
+    /// Same as [`TrayItem::new`] without blocking the executor while ksni or
+    /// zbus connect to the session bus.
+    pub async fn new_async(title: &str, icon: IconSource) -> Result<Self, TIError> {
+        let menu = Arc::new(Mutex::new(Menu::new()));
+
+        Ok(Self {
+            inner: api::TrayItemImpl::new_async(title, icon, Arc::clone(&menu)).await?,
+            menu,
+        })
+    }
+
+    pub async fn set_icon_async(&mut self, icon: IconSource) -> Result<(), TIError> {
+        self.inner.set_icon_async(icon).await
+    }
+
+    pub async fn set_menu_item_label_async(
+        &mut self,
+        label: &str,
+        id: MenuItemId,
+    ) -> Result<(), TIError> {
+        self.update_menu_async(|menu| menu.set_label(id, label))
+            .await
+    }
+
+    pub async fn set_enabled_async(
+        &mut self,
+        id: MenuItemId,
+        enabled: bool,
+    ) -> Result<(), TIError> {
+        self.update_menu_async(|menu| menu.set_enabled(id, enabled))
+            .await
+    }
+
+    pub async fn set_visible_async(
+        &mut self,
+        id: MenuItemId,
+        visible: bool,
+    ) -> Result<(), TIError> {
+        self.update_menu_async(|menu| menu.set_visible(id, visible))
+            .await
+    }
+
+    pub async fn set_checked_async(
+        &mut self,
+        id: MenuItemId,
+        checked: bool,
+    ) -> Result<(), TIError> {
+        self.update_menu_async(|menu| menu.set_checked(id, checked))
+            .await
+    }
+
+    pub async fn set_selected_async(
+        &mut self,
+        id: MenuItemId,
+        index: usize,
+    ) -> Result<(), TIError> {
+        self.update_menu_async(|menu| menu.set_selected(id, index))
+            .await
+    }
+
+    pub async fn remove_item_async(&mut self, id: MenuItemId) -> Result<(), TIError> {
+        self.update_menu_async(|menu| menu.remove(id).map(|_| ()))
+            .await
+    }
+
+    pub async fn clear_menu_async(&mut self) -> Result<(), TIError> {
+        self.update_menu_async(|menu| {
+            menu.clear();
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_menu_async<R, F>(&mut self, f: F) -> Result<R, TIError>
+    where
+        F: FnOnce(&mut Menu) -> Result<R, TIError>,
+    {
+        let ret = f(&mut self.menu.lock().unwrap())?;
+        self.inner.update_menu_async().await?;
+        Ok(ret)
+    }
+}
+
+// The Linux backends hand paths to D-Bus and C as UTF-8 strings
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus", feature = "libappindicator"),
+    not(feature = "mock")
+))]
+fn path_str(path: &Path) -> Result<&str, TIError> {
+    path.to_str()
+        .ok_or_else(|| TIError::new(format!("{} is not valid UTF-8", path.display())))
+}
+
+/// Handle used to fill a submenu, returned by [`TrayItem::add_submenu`].
+pub struct SubmenuHandle<'a> {
+    tray: &'a mut TrayItem,
+    id: MenuItemId,
+}
+
+impl SubmenuHandle<'_> {
+    pub fn id(&self) -> MenuItemId {
+        self.id
+    }
+
+    pub fn add_label(&mut self, label: &str) -> Result<MenuItemId, TIError> {
+        let parent = self.id;
+        self.tray
+            .update_menu(|menu| menu.add_label_to(parent, label))
+    }
+
+    pub fn add_item(&mut self, label: &str) -> Result<MenuItemId, TIError> {
+        let parent = self.id;
+        self.tray
+            .update_menu(|menu| menu.add_item_to(parent, label))
+    }
+
+    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> Result<MenuItemId, TIError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let parent = self.id;
+        self.tray
+            .update_menu(|menu| menu.add_menu_item_to(parent, label, cb))
+    }
+
+    pub fn add_check_item<F>(
+        &mut self,
+        label: &str,
+        checked: bool,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn(bool) + Send + Sync + 'static,
+    {
+        let parent = self.id;
+        self.tray
+            .update_menu(|menu| menu.add_check_item_to(parent, label, checked, cb))
+    }
+
+    pub fn add_radio_group<F>(
+        &mut self,
+        options: &[&str],
+        selected: usize,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let parent = self.id;
+        self.tray
+            .update_menu(|menu| menu.add_radio_group_to(parent, options, selected, cb))
+    }
+
+    pub fn insert_item_at(
+        &mut self,
+        index: usize,
+        item: MenuItemBuilder,
+    ) -> Result<MenuItemId, TIError> {
+        let parent = self.id;
+        self.tray
+            .update_menu(|menu| menu.insert_item_to(parent, index, item))
+    }
+
+    pub fn clear(&mut self) -> Result<(), TIError> {
+        let parent = self.id;
+        self.tray.update_menu(|menu| menu.clear_submenu(parent))
+    }
+
+    pub fn add_separator(&mut self) -> Result<MenuItemId, TIError> {
+        let parent = self.id;
+        self.tray.update_menu(|menu| menu.add_separator_to(parent))
+    }
+
+    pub fn add_submenu(&mut self, label: &str) -> Result<SubmenuHandle<'_>, TIError> {
+        let parent = self.id;
+        let id = self
+            .tray
+            .update_menu(|menu| menu.add_submenu_to(parent, label))?;
+        Ok(SubmenuHandle {
+            tray: self.tray,
+            id,
+        })
     }
 }
@@ -0,0 +1,1152 @@
+use {
+    crate::{
+        event::{Events, SharedEvents},
+        TIError, TrayEvent,
+    },
+    std::{
+        fmt,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    },
+};
+
+// The menu is shared between `TrayItem` and the backend so callbacks fired on a
+// backend thread see the same state as the public API.
+pub(crate) type SharedMenu = Arc<Mutex<Menu>>;
+
+// Every menu gets its own owner so ids can not be mixed up between trays
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(0);
+
+/// Opaque handle to a menu item, stays valid while other items are added,
+/// moved or removed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MenuItemId {
+    owner: u64,
+    item: u32,
+}
+
+impl MenuItemId {
+    // Counts up from 0 in every menu, ids are never reused
+    #[cfg(all(target_os = "linux", feature = "zbus", not(feature = "mock")))]
+    pub(crate) fn index(&self) -> u32 {
+        self.item
+    }
+}
+
+impl fmt::Display for MenuItemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.item)
+    }
+}
+
+#[derive(Clone)]
+enum Action {
+    Activate(Arc<dyn Fn() + Send + Sync + 'static>),
+    Toggle(Arc<dyn Fn(bool) + Send + Sync + 'static>),
+    Select(Arc<dyn Fn(usize) + Send + Sync + 'static>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MenuItemKind {
+    Label,
+    Action,
+    Check,
+    RadioGroup,
+    Separator,
+    Submenu,
+}
+
+#[derive(Clone)]
+pub struct MenuItem {
+    id: MenuItemId,
+    label: String,
+    kind: MenuItemKind,
+    enabled: bool,
+    visible: bool,
+    checked: bool,
+    options: Vec<String>,
+    selected: usize,
+    action: Option<Action>,
+    children: Vec<MenuItem>,
+}
+
+impl MenuItem {
+    pub fn id(&self) -> MenuItemId {
+        self.id
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn kind(&self) -> MenuItemKind {
+        self.kind
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// State of a check item, always `false` for every other kind.
+    pub fn is_checked(&self) -> bool {
+        self.checked
+    }
+
+    /// Labels of the options in a radio group, empty for every other kind.
+    pub fn options(&self) -> &[String] {
+        &self.options
+    }
+
+    /// Index of the selected option in a radio group.
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Items of a submenu, empty for every other kind.
+    pub fn children(&self) -> &[MenuItem] {
+        &self.children
+    }
+}
+
+/// An item that is not part of a menu yet, passed to [`Menu::insert_item_at`]
+/// to place any kind of item at a position. The menu gives it an id once it is
+/// inserted.
+pub struct MenuItemBuilder {
+    label: String,
+    kind: MenuItemKind,
+    enabled: bool,
+    visible: bool,
+    checked: bool,
+    options: Vec<String>,
+    selected: usize,
+    action: Option<Action>,
+}
+
+impl MenuItemBuilder {
+    fn new(label: &str, kind: MenuItemKind, action: Option<Action>) -> Self {
+        Self {
+            label: label.to_string(),
+            kind,
+            // Labels and separators only show, clicking them does nothing
+            enabled: !matches!(kind, MenuItemKind::Label | MenuItemKind::Separator),
+            visible: true,
+            checked: false,
+            options: Vec::new(),
+            selected: 0,
+            action,
+        }
+    }
+
+    pub fn label(label: &str) -> Self {
+        Self::new(label, MenuItemKind::Label, None)
+    }
+
+    /// An item without a callback, clicks are only reported as events.
+    pub fn item(label: &str) -> Self {
+        Self::new(label, MenuItemKind::Action, None)
+    }
+
+    pub fn menu_item<F>(label: &str, cb: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Self::new(
+            label,
+            MenuItemKind::Action,
+            Some(Action::Activate(Arc::new(cb))),
+        )
+    }
+
+    pub fn check_item<F>(label: &str, checked: bool, cb: F) -> Self
+    where
+        F: Fn(bool) + Send + Sync + 'static,
+    {
+        Self {
+            checked,
+            ..Self::new(
+                label,
+                MenuItemKind::Check,
+                Some(Action::Toggle(Arc::new(cb))),
+            )
+        }
+    }
+
+    pub fn radio_group<F>(options: &[&str], selected: usize, cb: F) -> Result<Self, TIError>
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        if selected >= options.len() {
+            return Err(TIError::new(format!(
+                "Selected option {} is out of range for {} options",
+                selected,
+                options.len()
+            )));
+        }
+
+        Ok(Self {
+            options: options.iter().map(|option| option.to_string()).collect(),
+            selected,
+            ..Self::new(
+                "",
+                MenuItemKind::RadioGroup,
+                Some(Action::Select(Arc::new(cb))),
+            )
+        })
+    }
+
+    pub fn separator() -> Self {
+        Self::new("", MenuItemKind::Separator, None)
+    }
+
+    pub fn submenu(label: &str) -> Self {
+        Self::new(label, MenuItemKind::Submenu, None)
+    }
+
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn visible(mut self, visible: bool) -> Self {
+        self.visible = visible;
+        self
+    }
+}
+
+/// Backend independent description of a tray menu.
+///
+/// Every backend renders from this model, so it can be built and inspected
+/// without a running desktop. A clone is detached from the original: its ids
+/// are its own, and it has no callbacks or event subscribers.
+pub struct Menu {
+    owner: u64,
+    items: Vec<MenuItem>,
+    next_id: u32,
+    // Bumped by every change, so renderers can skip redrawing an unchanged menu
+    revision: u64,
+    events: SharedEvents,
+}
+
+impl Default for Menu {
+    fn default() -> Self {
+        Self {
+            owner: NEXT_OWNER.fetch_add(1, Ordering::Relaxed),
+            items: Vec::new(),
+            next_id: 0,
+            revision: 0,
+            events: Arc::new(Events::default()),
+        }
+    }
+}
+
+// A clone must not reach the tray it was taken from, neither through its
+// callbacks and events nor through ids the tray would accept
+impl Clone for Menu {
+    fn clone(&self) -> Self {
+        let owner = NEXT_OWNER.fetch_add(1, Ordering::Relaxed);
+        Self {
+            owner,
+            items: self.items.iter().map(|item| detach(item, owner)).collect(),
+            next_id: self.next_id,
+            revision: self.revision,
+            events: Arc::new(Events::default()),
+        }
+    }
+}
+
+impl Menu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn items(&self) -> &[MenuItem] {
+        &self.items
+    }
+
+    pub fn get(&self, id: MenuItemId) -> Option<&MenuItem> {
+        find(&self.items, id)
+    }
+
+    pub(crate) fn events(&self) -> &SharedEvents {
+        &self.events
+    }
+
+    #[cfg(any(
+        test,
+        all(
+            target_os = "linux",
+            any(feature = "ksni", feature = "zbus"),
+            not(feature = "mock")
+        )
+    ))]
+    pub(crate) fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Check that `id` was handed out by this menu.
+    pub fn owns(&self, id: MenuItemId) -> Result<(), TIError> {
+        if id.owner != self.owner {
+            return Err(TIError::new(format!(
+                "Menu item {} belongs to another tray",
+                id
+            )));
+        }
+
+        Ok(())
+    }
+
+    pub fn add_label(&mut self, label: &str) -> MenuItemId {
+        let item = self.new_item(MenuItemBuilder::label(label));
+        self.push(item)
+    }
+
+    /// Add an item without a callback, clicks are only reported as events.
+    pub fn add_item(&mut self, label: &str) -> MenuItemId {
+        let item = self.new_item(MenuItemBuilder::item(label));
+        self.push(item)
+    }
+
+    pub fn add_menu_item<F>(&mut self, label: &str, cb: F) -> MenuItemId
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let item = self.new_item(MenuItemBuilder::menu_item(label, cb));
+        self.push(item)
+    }
+
+    pub fn add_check_item<F>(&mut self, label: &str, checked: bool, cb: F) -> MenuItemId
+    where
+        F: Fn(bool) + Send + Sync + 'static,
+    {
+        let item = self.new_item(MenuItemBuilder::check_item(label, checked, cb));
+        self.push(item)
+    }
+
+    /// Add a group of mutually exclusive options, `cb` receives the index of
+    /// the newly selected option.
+    pub fn add_radio_group<F>(
+        &mut self,
+        options: &[&str],
+        selected: usize,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let item = self.new_item(MenuItemBuilder::radio_group(options, selected, cb)?);
+        Ok(self.push(item))
+    }
+
+    pub fn add_separator(&mut self) -> MenuItemId {
+        let item = self.new_item(MenuItemBuilder::separator());
+        self.push(item)
+    }
+
+    pub fn add_submenu(&mut self, label: &str) -> MenuItemId {
+        let item = self.new_item(MenuItemBuilder::submenu(label));
+        self.push(item)
+    }
+
+    pub fn add_label_to(&mut self, parent: MenuItemId, label: &str) -> Result<MenuItemId, TIError> {
+        let item = self.new_item(MenuItemBuilder::label(label));
+        self.push_to(parent, item)
+    }
+
+    pub fn add_item_to(&mut self, parent: MenuItemId, label: &str) -> Result<MenuItemId, TIError> {
+        let item = self.new_item(MenuItemBuilder::item(label));
+        self.push_to(parent, item)
+    }
+
+    pub fn add_menu_item_to<F>(
+        &mut self,
+        parent: MenuItemId,
+        label: &str,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let item = self.new_item(MenuItemBuilder::menu_item(label, cb));
+        self.push_to(parent, item)
+    }
+
+    pub fn add_check_item_to<F>(
+        &mut self,
+        parent: MenuItemId,
+        label: &str,
+        checked: bool,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn(bool) + Send + Sync + 'static,
+    {
+        let item = self.new_item(MenuItemBuilder::check_item(label, checked, cb));
+        self.push_to(parent, item)
+    }
+
+    pub fn add_radio_group_to<F>(
+        &mut self,
+        parent: MenuItemId,
+        options: &[&str],
+        selected: usize,
+        cb: F,
+    ) -> Result<MenuItemId, TIError>
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let item = self.new_item(MenuItemBuilder::radio_group(options, selected, cb)?);
+        self.push_to(parent, item)
+    }
+
+    pub fn add_separator_to(&mut self, parent: MenuItemId) -> Result<MenuItemId, TIError> {
+        let item = self.new_item(MenuItemBuilder::separator());
+        self.push_to(parent, item)
+    }
+
+    pub fn add_submenu_to(
+        &mut self,
+        parent: MenuItemId,
+        label: &str,
+    ) -> Result<MenuItemId, TIError> {
+        let item = self.new_item(MenuItemBuilder::submenu(label));
+        self.push_to(parent, item)
+    }
+
+    /// Insert an item at `index` of the root menu, the index counts items as
+    /// they are stored in the model so a radio group takes one slot.
+    pub fn insert_item_at(
+        &mut self,
+        index: usize,
+        item: MenuItemBuilder,
+    ) -> Result<MenuItemId, TIError> {
+        let item = self.new_item(item);
+        let id = insert(&mut self.items, index, item)?;
+        self.revision += 1;
+        Ok(id)
+    }
+
+    pub fn insert_item_to(
+        &mut self,
+        parent: MenuItemId,
+        index: usize,
+        item: MenuItemBuilder,
+    ) -> Result<MenuItemId, TIError> {
+        let item = self.new_item(item);
+        let id = insert(&mut self.get_submenu_mut(parent)?.children, index, item)?;
+        self.revision += 1;
+        Ok(id)
+    }
+
+    /// Remove an item and everything below it. Ids are never reused, so ids of
+    /// the remaining items stay valid.
+    pub fn remove(&mut self, id: MenuItemId) -> Result<MenuItem, TIError> {
+        let (siblings, index) = self.get_siblings_mut(id)?;
+        let item = siblings.remove(index);
+        self.revision += 1;
+        Ok(item)
+    }
+
+    /// Move an item to `index` among its siblings.
+    pub fn move_item(&mut self, id: MenuItemId, index: usize) -> Result<(), TIError> {
+        let (siblings, from) = self.get_siblings_mut(id)?;
+        if index >= siblings.len() {
+            return Err(TIError::new(format!(
+                "Position {} is out of range for {} items",
+                index,
+                siblings.len()
+            )));
+        }
+
+        let item = siblings.remove(from);
+        siblings.insert(index, item);
+        self.revision += 1;
+        Ok(())
+    }
+
+    /// Remove every item from the root menu.
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.revision += 1;
+    }
+
+    pub fn clear_submenu(&mut self, id: MenuItemId) -> Result<(), TIError> {
+        self.get_submenu_mut(id)?.children.clear();
+        self.revision += 1;
+        Ok(())
+    }
+
+    pub fn set_label(&mut self, id: MenuItemId, label: &str) -> Result<(), TIError> {
+        self.get_mut(id)?.label = label.to_string();
+        self.revision += 1;
+        Ok(())
+    }
+
+    pub fn set_enabled(&mut self, id: MenuItemId, enabled: bool) -> Result<(), TIError> {
+        self.get_mut(id)?.enabled = enabled;
+        self.revision += 1;
+        Ok(())
+    }
+
+    pub fn set_visible(&mut self, id: MenuItemId, visible: bool) -> Result<(), TIError> {
+        self.get_mut(id)?.visible = visible;
+        self.revision += 1;
+        Ok(())
+    }
+
+    /// Change the state of a check item without running its callback.
+    pub fn set_checked(&mut self, id: MenuItemId, checked: bool) -> Result<(), TIError> {
+        self.get_check_mut(id)?.checked = checked;
+        self.revision += 1;
+        Ok(())
+    }
+
+    pub fn is_checked(&self, id: MenuItemId) -> Result<bool, TIError> {
+        self.owns(id)?;
+        match self.get(id) {
+            Some(item) if item.kind == MenuItemKind::Check => Ok(item.checked),
+            Some(_) => Err(TIError::new(format!(
+                "Menu item {} is not a check item",
+                id
+            ))),
+            None => Err(TIError::new(format!("No menu item with id {}", id))),
+        }
+    }
+
+    /// Change the selected option of a radio group without running its callback.
+    pub fn set_selected(&mut self, id: MenuItemId, index: usize) -> Result<(), TIError> {
+        let item = self.get_radio_group_mut(id)?;
+        if index >= item.options.len() {
+            return Err(TIError::new(format!(
+                "Radio group {} has no option {}",
+                id, index
+            )));
+        }
+
+        item.selected = index;
+        self.revision += 1;
+        Ok(())
+    }
+
+    pub fn selected(&self, id: MenuItemId) -> Result<usize, TIError> {
+        self.owns(id)?;
+        match self.get(id) {
+            Some(item) if item.kind == MenuItemKind::RadioGroup => Ok(item.selected),
+            Some(_) => Err(TIError::new(format!(
+                "Menu item {} is not a radio group",
+                id
+            ))),
+            None => Err(TIError::new(format!("No menu item with id {}", id))),
+        }
+    }
+
+    /// Act as if the user picked option `index` of a radio group.
+    pub fn select(&mut self, id: MenuItemId, index: usize) -> Result<(), TIError> {
+        if let Some(action) = self.trigger_select(id, index)? {
+            action();
+        }
+
+        Ok(())
+    }
+
+    /// Act as if the user clicked the item: toggle check items, run the callback
+    /// and send the event.
+    pub fn activate(&mut self, id: MenuItemId) -> Result<(), TIError> {
+        if let Some(action) = self.trigger(id)? {
+            action();
+        }
+
+        Ok(())
+    }
+
+    // Updates the item state for a click and returns the callback to run, so
+    // shared menus can be unlocked before user code is called.
+    fn trigger(&mut self, id: MenuItemId) -> Result<Option<Box<dyn FnOnce() + Send>>, TIError> {
+        let events = SharedEvents::clone(&self.events);
+        let item = self.get_interactive_mut(id)?;
+
+        Ok(match (item.kind, item.action.clone()) {
+            (MenuItemKind::Label | MenuItemKind::Separator, _) => {
+                return Err(TIError::new(format!("Menu item {} can not be clicked", id)))
+            }
+            (MenuItemKind::RadioGroup, _) => None,
+            (MenuItemKind::Check, action) => {
+                item.checked = !item.checked;
+                let checked = item.checked;
+                self.revision += 1;
+                Some(Box::new(move || {
+                    if let Some(Action::Toggle(cb)) = action {
+                        cb(checked);
+                    }
+                    events.send(TrayEvent::CheckToggled(id, checked));
+                }))
+            }
+            (_, action) => Some(Box::new(move || {
+                if let Some(Action::Activate(cb)) = action {
+                    cb();
+                }
+                events.send(TrayEvent::MenuItemActivated(id));
+            })),
+        })
+    }
+
+    fn trigger_select(
+        &mut self,
+        id: MenuItemId,
+        index: usize,
+    ) -> Result<Option<Box<dyn FnOnce() + Send>>, TIError> {
+        let events = SharedEvents::clone(&self.events);
+        self.get_interactive_mut(id)?;
+        // Picking the option already selected changes nothing, GTK does not
+        // even report it
+        if self.get_radio_group_mut(id)?.selected == index {
+            return Ok(None);
+        }
+        self.set_selected(id, index)?;
+
+        let action = self.get_mut(id)?.action.clone();
+        Ok(Some(Box::new(move || {
+            if let Some(Action::Select(cb)) = action {
+                cb(index);
+            }
+            events.send(TrayEvent::RadioSelected(id, index));
+        })))
+    }
+
+    fn get_mut(&mut self, id: MenuItemId) -> Result<&mut MenuItem, TIError> {
+        self.owns(id)?;
+        find_mut(&mut self.items, id)
+            .ok_or_else(|| TIError::new(format!("No menu item with id {}", id)))
+    }
+
+    // The list holding the item together with its position in that list
+    fn get_siblings_mut(&mut self, id: MenuItemId) -> Result<(&mut Vec<MenuItem>, usize), TIError> {
+        self.owns(id)?;
+        find_siblings_mut(&mut self.items, id)
+            .ok_or_else(|| TIError::new(format!("No menu item with id {}", id)))
+    }
+
+    // Disabled and hidden items can not be clicked by the user
+    fn get_interactive_mut(&mut self, id: MenuItemId) -> Result<&mut MenuItem, TIError> {
+        let item = self.get_mut(id)?;
+        if !item.enabled || !item.visible {
+            return Err(TIError::new(format!(
+                "Menu item {} is disabled or hidden",
+                id
+            )));
+        }
+
+        Ok(item)
+    }
+
+    fn get_check_mut(&mut self, id: MenuItemId) -> Result<&mut MenuItem, TIError> {
+        let item = self.get_mut(id)?;
+        match item.kind {
+            MenuItemKind::Check => Ok(item),
+            _ => Err(TIError::new(format!(
+                "Menu item {} is not a check item",
+                id
+            ))),
+        }
+    }
+
+    fn get_submenu_mut(&mut self, id: MenuItemId) -> Result<&mut MenuItem, TIError> {
+        let item = self.get_mut(id)?;
+        match item.kind {
+            MenuItemKind::Submenu => Ok(item),
+            _ => Err(TIError::new(format!("Menu item {} is not a submenu", id))),
+        }
+    }
+
+    fn get_radio_group_mut(&mut self, id: MenuItemId) -> Result<&mut MenuItem, TIError> {
+        let item = self.get_mut(id)?;
+        match item.kind {
+            MenuItemKind::RadioGroup => Ok(item),
+            _ => Err(TIError::new(format!(
+                "Menu item {} is not a radio group",
+                id
+            ))),
+        }
+    }
+
+    fn new_item(&mut self, item: MenuItemBuilder) -> MenuItem {
+        let id = MenuItemId {
+            owner: self.owner,
+            item: self.next_id,
+        };
+        self.next_id += 1;
+
+        MenuItem {
+            id,
+            label: item.label,
+            kind: item.kind,
+            enabled: item.enabled,
+            visible: item.visible,
+            checked: item.checked,
+            options: item.options,
+            selected: item.selected,
+            action: item.action,
+            children: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, item: MenuItem) -> MenuItemId {
+        let id = item.id;
+        self.items.push(item);
+        self.revision += 1;
+
+        id
+    }
+
+    fn push_to(&mut self, parent: MenuItemId, item: MenuItem) -> Result<MenuItemId, TIError> {
+        let id = item.id;
+        self.get_submenu_mut(parent)?.children.push(item);
+        self.revision += 1;
+
+        Ok(id)
+    }
+}
+
+// Clicks coming from a backend, or from the mock standing in for one
+#[cfg(any(
+    feature = "mock",
+    target_os = "windows",
+    target_os = "macos",
+    all(
+        target_os = "linux",
+        any(
+            feature = "ksni",
+            feature = "zbus",
+            feature = "libappindicator",
+            feature = "xembed"
+        )
+    )
+))]
+pub(crate) mod click {
+    use super::{MenuItemId, SharedMenu, TIError};
+
+    /// Handle a click coming from a backend. The lock is released before the
+    /// callback runs, and backends call this outside of their own locks, so the
+    /// callback is free to edit the menu and the tray.
+    #[cfg(not(feature = "mock"))]
+    pub(crate) fn activate(menu: &SharedMenu, id: MenuItemId) {
+        try_activate(menu, id).ok();
+    }
+
+    /// Same as [`activate`] for an option of a radio group.
+    #[cfg(not(feature = "mock"))]
+    pub(crate) fn select(menu: &SharedMenu, id: MenuItemId, index: usize) {
+        try_select(menu, id, index).ok();
+    }
+
+    // Backends drop clicks on stale items silently, the mock reports them
+    pub(crate) fn try_activate(menu: &SharedMenu, id: MenuItemId) -> Result<(), TIError> {
+        let action = menu.lock().unwrap().trigger(id)?;
+        if let Some(action) = action {
+            action();
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn try_select(
+        menu: &SharedMenu,
+        id: MenuItemId,
+        index: usize,
+    ) -> Result<(), TIError> {
+        let action = menu.lock().unwrap().trigger_select(id, index)?;
+        if let Some(action) = action {
+            action();
+        }
+
+        Ok(())
+    }
+}
+
+fn insert(items: &mut Vec<MenuItem>, index: usize, item: MenuItem) -> Result<MenuItemId, TIError> {
+    if index > items.len() {
+        return Err(TIError::new(format!(
+            "Position {} is out of range for {} items",
+            index,
+            items.len()
+        )));
+    }
+
+    let id = item.id;
+    items.insert(index, item);
+
+    Ok(id)
+}
+
+fn detach(item: &MenuItem, owner: u64) -> MenuItem {
+    MenuItem {
+        id: MenuItemId { owner, ..item.id },
+        action: None,
+        children: item
+            .children
+            .iter()
+            .map(|child| detach(child, owner))
+            .collect(),
+        ..item.clone()
+    }
+}
+
+fn find(items: &[MenuItem], id: MenuItemId) -> Option<&MenuItem> {
+    items.iter().find_map(|item| {
+        (item.id == id)
+            .then_some(item)
+            .or_else(|| find(&item.children, id))
+    })
+}
+
+fn find_mut(items: &mut [MenuItem], id: MenuItemId) -> Option<&mut MenuItem> {
+    for item in items {
+        if item.id == id {
+            return Some(item);
+        }
+
+        if let Some(found) = find_mut(&mut item.children, id) {
+            return Some(found);
+        }
+    }
+
+    None
+}
+
+fn find_siblings_mut(
+    items: &mut Vec<MenuItem>,
+    id: MenuItemId,
+) -> Option<(&mut Vec<MenuItem>, usize)> {
+    if let Some(index) = items.iter().position(|item| item.id == id) {
+        return Some((items, index));
+    }
+
+    items
+        .iter_mut()
+        .find_map(|item| find_siblings_mut(&mut item.children, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[test]
+    fn clones_are_detached() {
+        let clicks = Arc::new(AtomicUsize::new(0));
+        let mut menu = Menu::new();
+        let counter = Arc::clone(&clicks);
+        let item = menu.add_menu_item("Quit", move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let events = menu.events().subscribe();
+
+        let mut clone = menu.clone();
+        let cloned = clone.items()[0].id();
+        assert_eq!(clone.items()[0].label(), "Quit");
+        assert!(clone.owns(item).is_err());
+        assert!(menu.owns(cloned).is_err());
+        assert!(menu.owns(clone.add_item("New")).is_err());
+
+        clone.activate(cloned).unwrap();
+        assert_eq!(clicks.load(Ordering::Relaxed), 0);
+        assert!(events.try_recv().is_err());
+
+        menu.activate(item).unwrap();
+        assert_eq!(clicks.load(Ordering::Relaxed), 1);
+        assert!(events.try_recv().is_ok());
+    }
+
+    fn labels(items: &[MenuItem]) -> Vec<&str> {
+        items.iter().map(MenuItem::label).collect()
+    }
+
+    #[test]
+    fn items_are_added_in_order() {
+        let mut menu = Menu::new();
+        let label = menu.add_label("Status");
+        let item = menu.add_item("Open");
+        let separator = menu.add_separator();
+        let submenu = menu.add_submenu("More");
+        let child = menu.add_item_to(submenu, "Settings").unwrap();
+
+        assert_eq!(labels(menu.items()), ["Status", "Open", "", "More"]);
+        assert_eq!(menu.get(label).unwrap().kind(), MenuItemKind::Label);
+        assert!(!menu.get(label).unwrap().is_enabled());
+        assert_eq!(menu.get(item).unwrap().kind(), MenuItemKind::Action);
+        assert!(menu.get(item).unwrap().is_enabled());
+        assert_eq!(menu.get(separator).unwrap().kind(), MenuItemKind::Separator);
+        assert!(!menu.get(separator).unwrap().is_enabled());
+        assert_eq!(labels(menu.get(submenu).unwrap().children()), ["Settings"]);
+        assert_eq!(menu.get(child).unwrap().label(), "Settings");
+    }
+
+    #[test]
+    fn labels_and_separators_are_not_clicked() {
+        let mut menu = Menu::new();
+        let events = menu.events().subscribe();
+        let label = menu.add_label("Status");
+        let separator = menu.add_separator();
+
+        assert!(menu.activate(separator).is_err());
+        // Even enabled they stay plain text and lines
+        menu.set_enabled(label, true).unwrap();
+        menu.set_enabled(separator, true).unwrap();
+        assert!(menu.activate(label).is_err());
+        assert!(menu.activate(separator).is_err());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn check_items_toggle_when_clicked() {
+        let toggles = Arc::new(Mutex::new(Vec::new()));
+        let mut menu = Menu::new();
+        let events = menu.events().subscribe();
+        let states = Arc::clone(&toggles);
+        let check = menu.add_check_item("Check", true, move |checked| {
+            states.lock().unwrap().push(checked)
+        });
+
+        menu.activate(check).unwrap();
+        assert!(!menu.is_checked(check).unwrap());
+        menu.activate(check).unwrap();
+        assert!(menu.is_checked(check).unwrap());
+        // Set from code, the callback does not run
+        menu.set_checked(check, false).unwrap();
+        assert!(!menu.is_checked(check).unwrap());
+
+        assert_eq!(*toggles.lock().unwrap(), [false, true]);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [
+                TrayEvent::CheckToggled(check, false),
+                TrayEvent::CheckToggled(check, true),
+            ]
+        );
+        let item = menu.add_item("Item");
+        assert!(menu.set_checked(item, true).is_err());
+        assert!(menu.is_checked(item).is_err());
+    }
+
+    #[test]
+    fn radio_groups_select_one_option() {
+        let picks = Arc::new(Mutex::new(Vec::new()));
+        let mut menu = Menu::new();
+        let events = menu.events().subscribe();
+        let picked = Arc::clone(&picks);
+        let group = menu
+            .add_radio_group(&["Low", "Mid", "High"], 1, move |index| {
+                picked.lock().unwrap().push(index)
+            })
+            .unwrap();
+
+        menu.select(group, 2).unwrap();
+        assert_eq!(menu.selected(group).unwrap(), 2);
+        // The selected option again is no change
+        menu.select(group, 2).unwrap();
+        assert!(menu.select(group, 3).is_err());
+        menu.set_selected(group, 0).unwrap();
+        assert_eq!(menu.selected(group).unwrap(), 0);
+
+        assert_eq!(*picks.lock().unwrap(), [2]);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [TrayEvent::RadioSelected(group, 2)]
+        );
+        assert!(menu.add_radio_group(&["Only"], 1, |_| {}).is_err());
+        assert!(menu.set_selected(group, 3).is_err());
+    }
+
+    #[test]
+    fn items_can_be_disabled_and_hidden() {
+        let mut menu = Menu::new();
+        let submenu = menu.add_submenu("More");
+        let child = menu.add_item_to(submenu, "Child").unwrap();
+
+        for id in [submenu, child] {
+            let revision = menu.revision();
+            menu.set_enabled(id, false).unwrap();
+            assert!(!menu.get(id).unwrap().is_enabled());
+            assert!(menu.get(id).unwrap().is_visible());
+            menu.set_visible(id, false).unwrap();
+            assert!(!menu.get(id).unwrap().is_visible());
+            assert_eq!(menu.revision(), revision + 2);
+
+            menu.set_enabled(id, true).unwrap();
+            menu.set_visible(id, true).unwrap();
+            assert!(menu.get(id).unwrap().is_enabled());
+            assert!(menu.get(id).unwrap().is_visible());
+            assert_eq!(menu.revision(), revision + 4);
+        }
+
+        let revision = menu.revision();
+        let removed = menu.add_item("Removed");
+        menu.remove(removed).unwrap();
+        let unknown = menu.revision();
+        assert!(menu.set_enabled(removed, false).is_err());
+        assert!(menu.set_visible(removed, false).is_err());
+        assert_eq!(menu.revision(), unknown);
+        assert_eq!(unknown, revision + 2);
+    }
+
+    #[test]
+    fn ids_are_unique() {
+        let mut menu = Menu::new();
+        let first = menu.add_item("First");
+        let submenu = menu.add_submenu("More");
+        let child = menu.add_item_to(submenu, "Child").unwrap();
+
+        assert_ne!(first, submenu);
+        assert_ne!(submenu, child);
+        assert_ne!(first, child);
+        assert_eq!(menu.get(first).unwrap().id(), first);
+        assert_eq!(menu.get(child).unwrap().id(), child);
+    }
+
+    #[test]
+    fn labels_can_be_changed() {
+        let mut menu = Menu::new();
+        let submenu = menu.add_submenu("More");
+        let child = menu.add_item_to(submenu, "Child").unwrap();
+
+        menu.set_label(submenu, "Less").unwrap();
+        menu.set_label(child, "Kid").unwrap();
+        assert_eq!(menu.get(submenu).unwrap().label(), "Less");
+        assert_eq!(menu.get(child).unwrap().label(), "Kid");
+    }
+
+    #[test]
+    fn items_only_nest_in_submenus() {
+        let mut menu = Menu::new();
+        let item = menu.add_item("Open");
+
+        assert!(menu.add_item_to(item, "Child").is_err());
+        assert!(menu.get(item).unwrap().children().is_empty());
+    }
+
+    #[test]
+    fn menus_own_their_ids() {
+        let mut menu = Menu::new();
+        let mut other = Menu::new();
+        let id = menu.add_item("Open");
+        other.add_item("Open");
+
+        assert!(menu.owns(id).is_ok());
+        assert!(other.owns(id).is_err());
+        assert!(other.get(id).is_none());
+    }
+
+    #[test]
+    fn removed_items_take_their_children() {
+        let mut menu = Menu::new();
+        let first = menu.add_item("First");
+        let submenu = menu.add_submenu("More");
+        let child = menu.add_item_to(submenu, "Child").unwrap();
+        let last = menu.add_item("Last");
+
+        assert_eq!(menu.remove(submenu).unwrap().label(), "More");
+        assert_eq!(labels(menu.items()), ["First", "Last"]);
+        assert!(menu.get(child).is_none());
+        assert!(menu.remove(submenu).is_err());
+        // The ids of the other items stay valid
+        menu.set_label(first, "One").unwrap();
+        menu.set_label(last, "Two").unwrap();
+        assert_eq!(labels(menu.items()), ["One", "Two"]);
+    }
+
+    #[test]
+    fn items_are_inserted_at_positions() {
+        let mut menu = Menu::new();
+        menu.add_item("B");
+        let submenu = menu.add_submenu("More");
+        menu.insert_item_at(0, MenuItemBuilder::menu_item("A", || {}))
+            .unwrap();
+        let check = menu
+            .insert_item_at(3, MenuItemBuilder::check_item("C", true, |_| {}))
+            .unwrap();
+        let nested = menu
+            .insert_item_at(1, MenuItemBuilder::submenu("Nested").enabled(false))
+            .unwrap();
+        let group = MenuItemBuilder::radio_group(&["X", "Y"], 1, |_| {}).unwrap();
+        let group = menu.insert_item_to(submenu, 0, group).unwrap();
+        menu.insert_item_to(submenu, 0, MenuItemBuilder::separator())
+            .unwrap();
+
+        assert_eq!(labels(menu.items()), ["A", "Nested", "B", "More", "C"]);
+        assert_eq!(labels(menu.get(submenu).unwrap().children()), ["", ""]);
+        assert!(menu.is_checked(check).unwrap());
+        assert_eq!(menu.selected(group).unwrap(), 1);
+        assert!(!menu.get(nested).unwrap().is_enabled());
+        menu.add_item_to(nested, "Child").unwrap();
+        assert!(MenuItemBuilder::radio_group(&["X"], 1, |_| {}).is_err());
+        assert!(menu
+            .insert_item_at(6, MenuItemBuilder::item("Far"))
+            .is_err());
+        assert!(menu
+            .insert_item_to(submenu, 3, MenuItemBuilder::item("Far"))
+            .is_err());
+        assert_eq!(menu.items().len(), 5);
+    }
+
+    #[test]
+    fn items_move_among_their_siblings() {
+        let mut menu = Menu::new();
+        let a = menu.add_item("A");
+        menu.add_item("B");
+        let submenu = menu.add_submenu("More");
+        let one = menu.add_item_to(submenu, "One").unwrap();
+        menu.add_item_to(submenu, "Two").unwrap();
+
+        menu.move_item(a, 2).unwrap();
+        assert_eq!(labels(menu.items()), ["B", "More", "A"]);
+        menu.move_item(one, 1).unwrap();
+        assert_eq!(
+            labels(menu.get(submenu).unwrap().children()),
+            ["Two", "One"]
+        );
+        assert!(menu.move_item(a, 3).is_err());
+        assert!(menu.move_item(one, 2).is_err());
+    }
+
+    #[test]
+    fn foreign_ids_are_rejected() {
+        let mut menu = Menu::new();
+        let mut other = Menu::new();
+        let submenu = menu.add_submenu("More");
+        let check = menu.add_check_item("Check", false, |_| {});
+        let group = menu.add_radio_group(&["A", "B"], 0, |_| {}).unwrap();
+        // Same position in the other menu, only the owner tells them apart
+        let foreign = other.add_submenu("More");
+        assert_eq!(foreign.to_string(), submenu.to_string());
+
+        for id in [foreign, other.add_item("Item")] {
+            assert!(menu.owns(id).is_err());
+            assert!(menu.set_label(id, "Label").is_err());
+            assert!(menu.set_enabled(id, false).is_err());
+            assert!(menu.set_visible(id, false).is_err());
+            assert!(menu.add_item_to(id, "Child").is_err());
+            assert!(menu
+                .insert_item_to(id, 0, MenuItemBuilder::item("Child"))
+                .is_err());
+            assert!(menu.move_item(id, 0).is_err());
+            assert!(menu.activate(id).is_err());
+            assert!(menu.remove(id).is_err());
+        }
+        assert!(other.set_checked(check, true).is_err());
+        assert!(other.is_checked(check).is_err());
+        assert!(other.set_selected(group, 1).is_err());
+        assert!(other.selected(group).is_err());
+        assert!(other.select(group, 1).is_err());
+
+        assert_eq!(labels(menu.items()), ["More", "Check", ""]);
+        assert!(!menu.is_checked(check).unwrap());
+        assert_eq!(menu.selected(group).unwrap(), 0);
+    }
+}
@@ -0,0 +1,106 @@
+//! What the test harnesses share. Each starts a server once per test binary,
+//! a session bus or an X server, for the trays to show up on.
+
+use std::{
+    env,
+    io::{BufRead, BufReader},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+// Every test makes its own tray on the shared server, told apart by a title
+// after the calling line. Evaluates to the tray, what `$server.find` makes of
+// it and the title, or returns when the server is unavailable.
+macro_rules! tray {
+    ($server:expr, |$title:ident| $new:expr) => {{
+        let Some(server) = $server else {
+            eprintln!("test server not found, skipping");
+            return;
+        };
+        let $title = concat!(module_path!(), line!());
+        let tray = $new.unwrap();
+        (tray, server.find($title), $title)
+    }};
+    ($server:expr, $icon:expr) => {
+        tray!($server, |title| TrayItem::new(title, $icon))
+    };
+    ($server:expr, $icon:expr, $backend:expr) => {
+        tray!($server, |title| TrayItem::with_backend(
+            title, $icon, $backend
+        ))
+    };
+}
+
+/// Starts a server with `start` once per test binary. Panics when `name` is
+/// not installed, unless `TRAY_ITEM_SKIP_UNAVAILABLE` is set to have it
+/// return `None` so the test can skip itself.
+pub fn start_once<T: Sync>(
+    server: &'static OnceLock<Option<T>>,
+    name: &str,
+    start: impl FnOnce() -> Option<T>,
+) -> Option<&'static T> {
+    let server = server.get_or_init(start).as_ref();
+    assert!(
+        server.is_some() || env::var_os("TRAY_ITEM_SKIP_UNAVAILABLE").is_some(),
+        "{name} not found, set TRAY_ITEM_SKIP_UNAVAILABLE=1 to skip"
+    );
+    server
+}
+
+/// A server started through `sh`. It would outlive the test process, so the
+/// shell kills it once our end of its stdin closes, be it on drop, which then
+/// reaps the shell, or when the process exits.
+pub struct Daemon {
+    shell: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl Daemon {
+    /// Runs `command` with `args` as `$0`, `$1`... and returns the first line
+    /// it prints, `None` when it prints none.
+    pub fn spawn(command: &str, args: &[&str]) -> Option<(Self, String)> {
+        let mut shell = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{command} & exec >&-; read _; kill $!; wait $!"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let stdout = shell.stdout.take();
+        let daemon = Self {
+            stdin: shell.stdin.take(),
+            shell,
+        };
+        let mut line = String::new();
+        BufReader::new(stdout?).read_line(&mut line).ok()?;
+        let line = line.trim();
+
+        (!line.is_empty()).then(|| (daemon, line.to_string()))
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        self.shell.wait().ok();
+    }
+}
+
+/// Polls `f` until it returns `Some`, panicking after a timeout. The backends
+/// work on threads of their own, so changes show up a little later.
+pub fn eventually<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    let start = Instant::now();
+    loop {
+        if let Some(value) = f() {
+            return value;
+        }
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for the tray");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
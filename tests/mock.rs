@@ -0,0 +1,204 @@
+#![cfg(feature = "mock")]
+
+use {
+    std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    tray_item::{IconSource, MenuItemBuilder, PixelFormat, Pixmap, TrayEvent, TrayItem},
+};
+
+fn tray() -> TrayItem {
+    TrayItem::new("Mock", IconSource::Resource("tray-icon")).unwrap()
+}
+
+#[test]
+fn clicks_run_the_callbacks() {
+    let mut tray = tray();
+    let events = tray.events();
+    let clicks = Arc::new(AtomicUsize::new(0));
+    let item = {
+        let clicks = Arc::clone(&clicks);
+        tray.add_menu_item("Click", move || {
+            clicks.fetch_add(1, Ordering::SeqCst);
+        })
+        .unwrap()
+    };
+    let checked = Arc::new(Mutex::new(Vec::new()));
+    let check = {
+        let checked = Arc::clone(&checked);
+        tray.add_check_item("Check", false, move |state| {
+            checked.lock().unwrap().push(state)
+        })
+        .unwrap()
+    };
+
+    tray.inner_mut().activate(item).unwrap();
+    tray.inner_mut().activate(item).unwrap();
+    tray.inner_mut().activate(check).unwrap();
+    tray.inner_mut().activate(check).unwrap();
+
+    assert_eq!(clicks.load(Ordering::SeqCst), 2);
+    assert_eq!(*checked.lock().unwrap(), [true, false]);
+    assert!(!tray.is_checked(check).unwrap());
+    let received: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+        received,
+        [
+            TrayEvent::MenuItemActivated(item),
+            TrayEvent::MenuItemActivated(item),
+            TrayEvent::CheckToggled(check, true),
+            TrayEvent::CheckToggled(check, false),
+        ]
+    );
+}
+
+#[test]
+fn selecting_an_option_runs_the_callback() {
+    let mut tray = tray();
+    let picked = Arc::new(Mutex::new(Vec::new()));
+    let group = {
+        let picked = Arc::clone(&picked);
+        tray.add_radio_group(&["Low", "High"], 0, move |index| {
+            picked.lock().unwrap().push(index)
+        })
+        .unwrap()
+    };
+
+    tray.inner_mut().select(group, 1).unwrap();
+
+    assert_eq!(*picked.lock().unwrap(), [1]);
+    assert_eq!(tray.selected(group).unwrap(), 1);
+    assert!(tray.inner_mut().select(group, 2).is_err());
+    assert_eq!(*picked.lock().unwrap(), [1]);
+}
+
+#[test]
+fn only_own_items_are_clicked() {
+    let mut tray = tray();
+    let events = tray.events();
+    let mut other = self::tray();
+    let foreign = other.add_item("Other").unwrap();
+    let label = tray.add_label("Label").unwrap();
+    let item = tray.add_item("Item").unwrap();
+
+    assert!(tray.inner_mut().activate(foreign).is_err());
+    assert!(tray.inner_mut().activate(label).is_err());
+    tray.set_enabled(item, false).unwrap();
+    assert!(tray.inner_mut().activate(item).is_err());
+    assert_eq!(events.try_iter().count(), 0);
+
+    tray.set_enabled(item, true).unwrap();
+    tray.inner_mut().activate(item).unwrap();
+    assert_eq!(events.try_recv(), Ok(TrayEvent::MenuItemActivated(item)));
+}
+
+#[test]
+fn menu_changes_are_rendered() {
+    let mut tray = tray();
+    let updates = tray.inner_mut().menu_updates();
+
+    let item = tray.add_item("Item").unwrap();
+    tray.set_menu_item_label("Renamed", item).unwrap();
+    tray.add_submenu("More")
+        .unwrap()
+        .add_item("Nested")
+        .unwrap();
+    assert_eq!(tray.inner_mut().menu_updates(), updates + 4);
+
+    // Failed changes leave the rendered menu alone
+    assert!(tray.move_item(item, 5).is_err());
+    assert_eq!(tray.inner_mut().menu_updates(), updates + 4);
+
+    tray.remove_item(item).unwrap();
+    assert_eq!(tray.inner_mut().menu_updates(), updates + 5);
+    assert_eq!(tray.menu().items().len(), 1);
+}
+
+#[test]
+fn any_kind_is_inserted() {
+    let mut tray = tray();
+    let events = tray.events();
+    tray.add_item("Last").unwrap();
+    let check = tray
+        .insert_item_at(0, MenuItemBuilder::check_item("Check", false, |_| {}))
+        .unwrap();
+    let mut submenu = tray
+        .insert_item_at(0, MenuItemBuilder::submenu("More"))
+        .and_then(|id| tray.submenu(id))
+        .unwrap();
+    let group = MenuItemBuilder::radio_group(&["Low", "High"], 0, |_| {}).unwrap();
+    let group = submenu.insert_item_at(0, group).unwrap();
+
+    tray.inner_mut().activate(check).unwrap();
+    tray.inner_mut().select(group, 1).unwrap();
+    let labels: Vec<_> = tray
+        .menu()
+        .items()
+        .iter()
+        .map(|item| item.label().to_string())
+        .collect();
+    assert_eq!(labels, ["More", "Check", "Last"]);
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        [
+            TrayEvent::CheckToggled(check, true),
+            TrayEvent::RadioSelected(group, 1),
+        ]
+    );
+}
+
+#[test]
+fn bad_pixels_are_rejected() {
+    let mut tray = tray();
+    let pixels = |width, height, len| IconSource::Pixels {
+        width,
+        height,
+        format: PixelFormat::Rgba8,
+        data: vec![0; len],
+    };
+
+    assert!(tray.set_icon(pixels(2, 2, 16)).is_ok());
+    assert!(tray.set_icon(pixels(2, 2, 15)).is_err());
+    assert!(tray.set_icon(pixels(0, 2, 0)).is_err());
+    assert!(tray.set_icon(pixels(-1, 2, 8)).is_err());
+    assert!(TrayItem::new("Mock", pixels(2, 2, 3)).is_err());
+
+    let pixmap = |size, len| Pixmap {
+        width: size,
+        height: size,
+        format: PixelFormat::Argb32,
+        data: vec![0; len],
+    };
+    assert!(tray
+        .set_icon(IconSource::Pixmaps(vec![pixmap(1, 4), pixmap(2, 16)]))
+        .is_ok());
+    let pixmaps = IconSource::Pixmaps(vec![pixmap(1, 4), pixmap(2, 4)]);
+    assert!(tray.set_icon(pixmaps).is_err());
+    assert!(tray.set_attention_icon(pixels(1, 1, 0)).is_err());
+    assert!(tray.set_overlay_icon(Some(pixels(1, 1, 0))).is_err());
+
+    // The last good icon stays
+    assert!(matches!(
+        tray.inner_mut().icon(),
+        IconSource::Pixmaps(pixmaps) if pixmaps.len() == 2
+    ));
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus", feature = "xembed")
+))]
+#[test]
+fn bad_data_is_rejected() {
+    let mut tray = tray();
+    let data = |len| IconSource::Data {
+        width: 2,
+        height: 2,
+        data: vec![0; len],
+    };
+
+    assert!(tray.set_icon(data(16)).is_ok());
+    assert!(tray.set_icon(data(0)).is_err());
+    assert!(tray.set_icon(data(12)).is_err());
+}
@@ -0,0 +1,322 @@
+#![cfg(all(
+    target_os = "linux",
+    any(feature = "ksni", feature = "zbus"),
+    not(feature = "mock")
+))]
+
+#[macro_use]
+mod common;
+mod support;
+
+use {
+    common::eventually,
+    std::sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    std::time::Duration,
+    support::{session, Pixmaps, ToolTip},
+    tray_item::{Backend, IconSource, PixelFormat, Pixmap, TrayEvent, TrayItem, TrayStatus},
+};
+
+fn pixels(width: i32, height: i32) -> IconSource {
+    IconSource::Pixels {
+        width,
+        height,
+        format: PixelFormat::Rgba8,
+        data: [0x11, 0x22, 0x33, 0x44].repeat((width * height) as usize),
+    }
+}
+
+#[test]
+fn exports_title_and_status() {
+    let (mut tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    assert_eq!(
+        item.property::<String>("Title"),
+        item.property::<String>("Id")
+    );
+    assert_eq!(item.property::<String>("IconName"), "tray-icon");
+    assert_eq!(item.property::<String>("Status"), "Active");
+
+    tray.set_status(TrayStatus::NeedsAttention).unwrap();
+    assert_eq!(item.property::<String>("Status"), "NeedsAttention");
+
+    tray.set_title_label(Some("42°C")).unwrap();
+    assert_eq!(item.property::<String>("Title"), "42°C");
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn starts_on_an_executor() {
+    let (tray, item, _) = tray!(session(), |title| async_io::block_on(TrayItem::new_async(
+        title,
+        IconSource::Resource("tray-icon")
+    )));
+
+    assert_eq!(item.property::<String>("IconName"), "tray-icon");
+    drop(tray);
+}
+
+#[test]
+fn picks_sni_while_a_watcher_runs() {
+    let (tray, _item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    #[cfg(feature = "ksni")]
+    assert_eq!(tray.backend(), Backend::Ksni);
+    #[cfg(not(feature = "ksni"))]
+    assert_eq!(tray.backend(), Backend::Zbus);
+}
+
+#[cfg(all(feature = "ksni", feature = "zbus"))]
+#[test]
+fn forced_backend_is_used() {
+    let (tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"), Backend::Zbus);
+
+    assert_eq!(tray.backend(), Backend::Zbus);
+    assert_eq!(item.property::<String>("IconName"), "tray-icon");
+}
+
+#[test]
+fn icon_pixmap_keeps_width_and_height_apart() {
+    let (_tray, item, _) = tray!(session(), pixels(4, 2));
+
+    let pixmaps: Pixmaps = item.property("IconPixmap");
+    assert_eq!(pixmaps.len(), 1);
+    let (width, height, data) = &pixmaps[0];
+    assert_eq!((*width, *height), (4, 2));
+    // ARGB32 in network byte order
+    assert_eq!(&data[..4], &[0x44, 0x11, 0x22, 0x33]);
+    assert_eq!(data.len(), 4 * 2 * 4);
+}
+
+#[test]
+fn exports_every_pixmap_size() {
+    let (mut tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    let pixmap = |size: i32| Pixmap {
+        width: size,
+        height: size,
+        format: PixelFormat::Argb32,
+        data: vec![0xff; (size * size * 4) as usize],
+    };
+    tray.set_icon(IconSource::Pixmaps(vec![pixmap(16), pixmap(32)]))
+        .unwrap();
+
+    let pixmaps: Pixmaps = item.property("IconPixmap");
+    let sizes: Vec<_> = pixmaps.iter().map(|(w, h, _)| (*w, *h)).collect();
+    assert_eq!(sizes, [(16, 16), (32, 32)]);
+}
+
+#[test]
+fn animation_interval_is_checked() {
+    let (mut tray, _item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    let frames = || vec![pixels(16, 16), pixels(16, 16)];
+    assert!(tray.set_animated_icon(frames(), Duration::ZERO).is_err());
+    assert!(tray
+        .set_animated_icon(frames(), Duration::from_millis(1))
+        .is_err());
+    #[cfg(feature = "ksni")]
+    assert_eq!(
+        tray.set_animated_icon(frames(), Duration::from_millis(50))
+            .is_err(),
+        tray.backend() == Backend::Ksni
+    );
+    assert!(tray
+        .set_animated_icon(frames(), Duration::from_millis(100))
+        .is_ok());
+    tray.stop_animation().unwrap();
+}
+
+#[test]
+fn exports_tooltip() {
+    let (mut tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    tray.set_tooltip("Syncing").unwrap();
+
+    let (icon, _, title, description): ToolTip = item.property("ToolTip");
+    assert_eq!((icon.as_str(), title.as_str()), ("", "Syncing"));
+    assert_eq!(description, "");
+}
+
+#[test]
+fn menu_layout_follows_the_model() {
+    let (mut tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    tray.add_label("Label").unwrap();
+    let check = tray.add_check_item("Check", true, |_| {}).unwrap();
+    tray.add_separator().unwrap();
+    tray.add_submenu("More")
+        .unwrap()
+        .add_menu_item("Inner", || {})
+        .unwrap();
+    tray.add_radio_group(&["One", "Two"], 1, |_| {}).unwrap();
+
+    let layout = eventually(|| Some(item.layout()).filter(|l| l.children.len() == 6));
+    assert_eq!(
+        layout.labels(),
+        ["Label", "Check", "", "More", "One", "Two"]
+    );
+    assert!(!layout.children[0].enabled);
+    assert_eq!(layout.children[1].toggle_type, "checkmark");
+    assert_eq!(layout.children[1].toggle_state, 1);
+    assert_eq!(layout.children[2].kind, "separator");
+    assert_eq!(layout.children[3].labels(), ["Inner"]);
+    assert_eq!(layout.children[4].toggle_state, 0);
+    assert_eq!(layout.children[5].toggle_state, 1);
+
+    tray.set_enabled(check, false).unwrap();
+    let layout = eventually(|| Some(item.layout()).filter(|l| !l.children[1].enabled));
+    assert!(layout.children[3].enabled);
+}
+
+#[test]
+fn menu_revision_follows_the_shape() {
+    let (mut tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    let check = tray.add_check_item("Check", false, |_| {}).unwrap();
+    eventually(|| Some(item.layout()).filter(|l| l.children.len() == 1));
+    let revision = item.revision();
+
+    tray.set_checked(check, true).unwrap();
+    eventually(|| Some(item.layout()).filter(|l| l.children[0].toggle_state == 1));
+    // zbus sends property changes without a new revision, ksni diffs the
+    // menu itself so that is up to the ksni version
+    #[cfg(feature = "zbus")]
+    if tray.backend() == Backend::Zbus {
+        assert_eq!(item.revision(), revision);
+    }
+
+    let updates = item.layout_updates();
+    tray.add_label("Label").unwrap();
+    let (new_revision, _) = updates.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert!(new_revision > revision);
+    eventually(|| Some(item.layout()).filter(|l| l.children.len() == 2));
+    assert!(item.revision() > revision);
+}
+
+// ksni numbers its items by position, the zbus backend keeps them
+#[cfg(feature = "zbus")]
+#[test]
+fn menu_ids_survive_changes() {
+    use tray_item::MenuItemBuilder;
+
+    let (mut tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"), Backend::Zbus);
+
+    let (tx, rx) = mpsc::channel();
+    tray.add_item("First").unwrap();
+    tray.add_radio_group(&["One", "Two"], 0, |_| {}).unwrap();
+    let mut submenu = tray.add_submenu("More").unwrap();
+    let submenu_id = submenu.id();
+    submenu.add_item("Inner").unwrap();
+    let last = tray
+        .add_menu_item("Last", move || tx.send(()).unwrap())
+        .unwrap();
+    let before = eventually(|| Some(item.layout()).filter(|l| l.children.len() == 5));
+
+    let updates = item.layout_updates();
+    tray.insert_item_at(0, MenuItemBuilder::item("Inserted"))
+        .unwrap();
+    let (_, parent) = updates.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(parent, 0);
+    tray.submenu(submenu_id)
+        .unwrap()
+        .add_item("Nested")
+        .unwrap();
+    let (_, parent) = updates.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(parent, before.children[3].id);
+
+    let after = eventually(|| Some(item.layout()).filter(|l| l.children.len() == 6));
+    let ids = |layout: &support::Node| -> Vec<i32> {
+        layout.children.iter().map(|child| child.id).collect()
+    };
+    assert_eq!(ids(&after)[1..], ids(&before));
+    assert_eq!(after.children[4].labels(), ["Inner", "Nested"]);
+    assert_eq!(
+        after.children[4].children[0].id,
+        before.children[3].children[0].id
+    );
+
+    // A click on an id from before the changes still reaches its item
+    item.click(before.children[4].id);
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    tray.remove_item(last).unwrap();
+    eventually(|| Some(item.layout()).filter(|l| l.children.len() == 5));
+}
+
+#[test]
+fn menu_clicks_run_callbacks() {
+    let (mut tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    let (tx, rx) = mpsc::channel();
+    let id = tray
+        .add_menu_item("Click me", move || tx.send(()).unwrap())
+        .unwrap();
+    let events = tray.events();
+
+    let layout = eventually(|| Some(item.layout()).filter(|l| !l.children.is_empty()));
+    item.click(layout.children[0].id);
+
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(2)),
+        Ok(TrayEvent::MenuItemActivated(id))
+    );
+}
+
+#[test]
+fn callbacks_can_change_the_tray() {
+    let (tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    let tray = Arc::new(Mutex::new(tray));
+    let shared = Arc::clone(&tray);
+    let (tx, rx) = mpsc::channel();
+    tray.lock()
+        .unwrap()
+        .add_menu_item("Rename", move || {
+            let mut tray = shared.lock().unwrap();
+            tray.set_title_label(Some("Renamed")).unwrap();
+            tray.add_label("Added").unwrap();
+            tx.send(()).unwrap();
+        })
+        .unwrap();
+
+    let layout = eventually(|| Some(item.layout()).filter(|l| !l.children.is_empty()));
+    item.click(layout.children[0].id);
+
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    eventually(|| Some(item.layout()).filter(|l| l.children.len() == 2));
+    assert_eq!(item.property::<String>("Title"), "Renamed");
+}
+
+#[test]
+fn icon_clicks_are_reported() {
+    let (tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    let events = tray.events();
+    item.call("Activate", (10i32, 20i32));
+    item.call("Scroll", (-1i32, "horizontal"));
+
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(2)),
+        Ok(TrayEvent::IconActivated { x: 10, y: 20 })
+    );
+    assert!(matches!(
+        events.recv_timeout(Duration::from_secs(2)),
+        Ok(TrayEvent::Scrolled { delta: -1, .. })
+    ));
+    assert_eq!(
+        events.recv_timeout(Duration::from_millis(100)),
+        Err(RecvTimeoutError::Timeout)
+    );
+}
+
+#[test]
+fn dropped_trays_leave_the_bus() {
+    let (tray, item, _) = tray!(session(), IconSource::Resource("tray-icon"));
+
+    assert!(item.is_on_bus());
+    drop(tray);
+    eventually(|| (!item.is_on_bus()).then_some(()));
+}
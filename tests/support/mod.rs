@@ -0,0 +1,299 @@
+//! A private session bus with a fake `org.kde.StatusNotifierWatcher`, so the
+//! ksni and zbus backends can be driven and inspected without a desktop.
+
+use {
+    crate::common::{eventually, start_once, Daemon},
+    std::{
+        collections::HashMap,
+        env, fs,
+        sync::{mpsc, Mutex, OnceLock},
+        thread,
+    },
+    zbus::{
+        blocking::{connection, fdo::DBusProxy, proxy, Connection, Proxy},
+        message::Header,
+        names::BusName,
+        proxy::CacheProperties,
+        zvariant::{OwnedValue, Structure, Value},
+    },
+};
+
+const WATCHER: &str = "org.kde.StatusNotifierWatcher";
+const SNI_PATH: &str = "/StatusNotifierItem";
+const SNI_INTERFACE: &str = "org.kde.StatusNotifierItem";
+const MENU_PATH: &str = "/MenuBar";
+const MENU_INTERFACE: &str = "com.canonical.dbusmenu";
+
+// Id, properties and children of a dbusmenu item
+type Entry = (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>);
+
+/// `IconPixmap` and friends, `(width, height, ARGB32 data)` per size.
+pub type Pixmaps = Vec<(i32, i32, Vec<u8>)>;
+
+/// `ToolTip`, `(icon name, icon pixmaps, title, description)`.
+pub type ToolTip = (String, Pixmaps, String, String);
+
+/// The bus and watcher, started once per test binary.
+pub struct Session {
+    address: String,
+    _watcher: Connection,
+    _daemon: Daemon,
+}
+
+/// Starts the bus and watcher and points `DBUS_SESSION_BUS_ADDRESS` at them
+/// for the trays, see [`start_once`].
+pub fn session() -> Option<&'static Session> {
+    static SESSION: OnceLock<Option<Session>> = OnceLock::new();
+    start_once(&SESSION, "dbus-daemon", || {
+        let session = Session::start()?;
+        // libdbus reads the address once per process, so the bus can't change
+        // later. Every test asks for it before making a tray, and the harness
+        // itself always connects to `address`.
+        env::set_var("DBUS_SESSION_BUS_ADDRESS", &session.address);
+        Some(session)
+    })
+}
+
+impl Session {
+    fn start() -> Option<Self> {
+        let config = env::temp_dir().join(format!("tray-item-test-{}.conf", std::process::id()));
+        fs::write(
+            &config,
+            r#"<busconfig>
+  <type>session</type>
+  <listen>unix:tmpdir=/tmp</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>"#,
+        )
+        .unwrap();
+        let daemon = Daemon::spawn(
+            r#"dbus-daemon --nofork --print-address --config-file="$0""#,
+            &[config.to_str().unwrap()],
+        );
+        fs::remove_file(&config).ok();
+        let (daemon, address) = daemon?;
+
+        let watcher = connection::Builder::address(address.as_str())
+            .and_then(|builder| builder.name(WATCHER))
+            .and_then(|builder| builder.serve_at("/StatusNotifierWatcher", Watcher::default()))
+            .and_then(|builder| builder.build())
+            .unwrap();
+
+        Some(Self {
+            address,
+            _watcher: watcher,
+            _daemon: daemon,
+        })
+    }
+
+    /// Waits for the item whose `Id`, the tray title, is `id` to register.
+    pub fn find(&self, id: &str) -> Item {
+        let conn = connection::Builder::address(self.address.as_str())
+            .and_then(|builder| builder.build())
+            .unwrap();
+        let watcher = uncached(&conn, WATCHER, "/StatusNotifierWatcher", WATCHER);
+        let service = eventually(|| {
+            let registered: Vec<String> =
+                watcher.get_property("RegisteredStatusNotifierItems").ok()?;
+            registered.into_iter().find(|service| {
+                uncached(&conn, service.clone(), SNI_PATH, SNI_INTERFACE)
+                    .get_property::<String>("Id")
+                    .is_ok_and(|item_id| item_id == id)
+            })
+        });
+
+        Item { conn, service }
+    }
+}
+
+#[derive(Default)]
+struct Watcher {
+    // Service names of the registered items
+    registered: Mutex<Vec<String>>,
+}
+
+#[zbus::interface(name = "org.kde.StatusNotifierWatcher")]
+impl Watcher {
+    fn register_status_notifier_item(&self, service: &str, #[zbus(header)] header: Header<'_>) {
+        // Items may register an object path, the sender is the service then
+        let service = match header.sender() {
+            Some(sender) if service.starts_with('/') => sender.to_string(),
+            _ => service.to_string(),
+        };
+        self.registered.lock().unwrap().push(service);
+    }
+
+    #[zbus(property)]
+    fn is_status_notifier_host_registered(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn protocol_version(&self) -> i32 {
+        0
+    }
+
+    #[zbus(property)]
+    fn registered_status_notifier_items(&self) -> Vec<String> {
+        self.registered.lock().unwrap().clone()
+    }
+}
+
+/// An item registered with the fake watcher, seen from a separate connection
+/// the way a host would.
+pub struct Item {
+    conn: Connection,
+    service: String,
+}
+
+impl Item {
+    /// Whether the item's service is still on the bus.
+    pub fn is_on_bus(&self) -> bool {
+        let name = BusName::try_from(self.service.as_str()).unwrap();
+        DBusProxy::new(&self.conn)
+            .and_then(|bus| bus.name_has_owner(name).map_err(zbus::Error::from))
+            .unwrap()
+    }
+
+    pub fn property<T>(&self, name: &str) -> T
+    where
+        T: TryFrom<OwnedValue>,
+        T::Error: Into<zbus::Error>,
+    {
+        self.proxy(SNI_PATH, SNI_INTERFACE)
+            .get_property(name)
+            .unwrap()
+    }
+
+    /// Calls a method of the item, like a host does for clicks and scrolls.
+    pub fn call(&self, method: &str, args: impl Into<Structure<'static>>) {
+        self.proxy(SNI_PATH, SNI_INTERFACE)
+            .call_method(method, &args.into())
+            .unwrap();
+    }
+
+    pub fn layout(&self) -> Node {
+        Node::new(self.get_layout().1)
+    }
+
+    /// Revision of the menu layout, which only changes with its shape.
+    pub fn revision(&self) -> u32 {
+        self.get_layout().0
+    }
+
+    fn get_layout(&self) -> (u32, Entry) {
+        self.proxy(MENU_PATH, MENU_INTERFACE)
+            .call("GetLayout", &(0i32, -1i32, Vec::<&str>::new()))
+            .unwrap()
+    }
+
+    /// `LayoutUpdated` signals of the menu as `(revision, parent)`, from now on.
+    pub fn layout_updates(&self) -> mpsc::Receiver<(u32, i32)> {
+        let (tx, rx) = mpsc::channel();
+        let signals = self
+            .proxy(MENU_PATH, MENU_INTERFACE)
+            .receive_signal("LayoutUpdated")
+            .unwrap();
+        thread::spawn(move || {
+            for signal in signals {
+                let update = signal.body().deserialize().unwrap();
+                if tx.send(update).is_err() {
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
+
+    /// Clicks the menu item with the dbusmenu `id`.
+    pub fn click(&self, id: i32) {
+        self.proxy(MENU_PATH, MENU_INTERFACE)
+            .call_method("Event", &(id, "clicked", Value::from(0i32), 0u32))
+            .unwrap();
+    }
+
+    fn proxy(&self, path: &'static str, interface: &'static str) -> Proxy<'static> {
+        uncached(&self.conn, self.service.clone(), path, interface)
+    }
+}
+
+// ksni announces no property changes, so nothing may come from a cache
+fn uncached<D>(
+    conn: &Connection,
+    destination: D,
+    path: &'static str,
+    interface: &'static str,
+) -> Proxy<'static>
+where
+    D: TryInto<BusName<'static>>,
+    D::Error: Into<zbus::Error>,
+{
+    proxy::Builder::new(conn)
+        .destination(destination)
+        .and_then(|builder| builder.path(path))
+        .and_then(|builder| builder.interface(interface))
+        .map(|builder| builder.cache_properties(CacheProperties::No))
+        .and_then(|builder| builder.build())
+        .unwrap()
+}
+
+/// A dbusmenu item with the properties the tests look at. Unset properties
+/// have their dbusmenu defaults.
+#[derive(Debug)]
+pub struct Node {
+    pub id: i32,
+    pub kind: String,
+    pub label: String,
+    pub enabled: bool,
+    pub visible: bool,
+    pub toggle_type: String,
+    pub toggle_state: i64,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    fn new((id, properties, children): Entry) -> Self {
+        let mut node = Node {
+            id,
+            kind: "standard".to_string(),
+            label: String::new(),
+            enabled: true,
+            visible: true,
+            toggle_type: String::new(),
+            toggle_state: -1,
+            children: vec![],
+        };
+        for (key, value) in properties {
+            match key.as_str() {
+                "type" => node.kind = value.try_into().unwrap(),
+                "label" => node.label = value.try_into().unwrap(),
+                "enabled" => node.enabled = value.try_into().unwrap(),
+                "visible" => node.visible = value.try_into().unwrap(),
+                "toggle-type" => node.toggle_type = value.try_into().unwrap(),
+                "toggle-state" => node.toggle_state = i32::try_from(value).unwrap().into(),
+                _ => (),
+            }
+        }
+        // Children are variants holding `(ia{sv}av)` structs
+        node.children = children
+            .into_iter()
+            .map(|child| Node::new(child.try_into().unwrap()))
+            .collect();
+
+        node
+    }
+
+    /// Labels of the children, handy for comparing the whole level at once.
+    pub fn labels(&self) -> Vec<&str> {
+        self.children
+            .iter()
+            .map(|child| child.label.as_str())
+            .collect()
+    }
+}
@@ -0,0 +1,133 @@
+#![cfg(all(target_os = "linux", feature = "xembed", not(feature = "mock")))]
+
+#[macro_use]
+mod common;
+mod xvfb;
+
+use {
+    common::eventually,
+    std::{sync::mpsc, time::Duration},
+    tray_item::{
+        Backend, IconSource, PixelFormat, ScrollOrientation, TrayEvent, TrayItem, TrayStatus,
+    },
+    xvfb::{display, ICON_SIZE},
+};
+
+// The backend is forced so other enabled ones can't take over
+const XEMBED: Backend = Backend::XEmbed;
+
+fn solid(rgb: u32) -> IconSource {
+    let [_, r, g, b] = rgb.to_be_bytes();
+    IconSource::Pixels {
+        width: 2,
+        height: 2,
+        format: PixelFormat::Rgba8,
+        data: [r, g, b, 0xff].repeat(4),
+    }
+}
+
+#[test]
+fn named_icons_are_rejected() {
+    // Checked before connecting, so this needs no display
+    assert!(
+        TrayItem::with_backend("named", IconSource::Resource("tray-icon"), Backend::XEmbed)
+            .is_err()
+    );
+}
+
+#[test]
+fn docks_and_paints_the_icon() {
+    let (mut tray, icon, _) = tray!(display(), solid(0xff0000), XEMBED);
+
+    let middle = ICON_SIZE as i16 / 2;
+    eventually(|| Some(()).filter(|_| icon.is_mapped()));
+    eventually(|| Some(()).filter(|_| icon.pixel(middle, middle) == 0xff0000));
+
+    tray.set_icon(solid(0x0000ff)).unwrap();
+    eventually(|| Some(()).filter(|_| icon.pixel(middle, middle) == 0x0000ff));
+}
+
+#[test]
+fn transparent_pixels_show_the_tray() {
+    // Opaque red on the left, transparent white on the right
+    let (_tray, icon, _) = tray!(
+        display(),
+        IconSource::Pixels {
+            width: 2,
+            height: 1,
+            format: PixelFormat::Rgba8,
+            data: vec![0xff, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x00],
+        },
+        XEMBED
+    );
+
+    let (quarter, middle) = (ICON_SIZE as i16 / 4, ICON_SIZE as i16 / 2);
+    eventually(|| Some(()).filter(|_| icon.pixel(quarter, middle) == 0xff0000));
+    // The stub tray is black
+    assert_eq!(icon.pixel(3 * quarter, middle), 0x000000);
+}
+
+#[test]
+fn passive_items_are_hidden() {
+    let (mut tray, icon, _) = tray!(display(), solid(0xff0000), XEMBED);
+    eventually(|| Some(()).filter(|_| icon.is_mapped()));
+
+    tray.set_status(TrayStatus::Passive).unwrap();
+    eventually(|| Some(()).filter(|_| !icon.is_mapped()));
+
+    tray.set_status(TrayStatus::Active).unwrap();
+    eventually(|| Some(()).filter(|_| icon.is_mapped()));
+}
+
+#[test]
+fn icon_clicks_are_reported() {
+    let (mut tray, icon, _) = tray!(display(), solid(0xff0000), XEMBED);
+
+    tray.on_activate(|_, _| {}).unwrap();
+    let events = tray.events();
+    icon.click(1);
+    icon.click(2);
+    icon.click(5);
+
+    let timeout = Duration::from_secs(2);
+    assert_eq!(
+        events.recv_timeout(timeout),
+        Ok(TrayEvent::IconActivated { x: 104, y: 204 })
+    );
+    assert_eq!(
+        events.recv_timeout(timeout),
+        Ok(TrayEvent::SecondaryActivated { x: 104, y: 204 })
+    );
+    assert_eq!(
+        events.recv_timeout(timeout),
+        Ok(TrayEvent::Scrolled {
+            delta: -1,
+            orientation: ScrollOrientation::Vertical
+        })
+    );
+}
+
+#[test]
+fn menu_clicks_run_callbacks() {
+    let (mut tray, icon, title) = tray!(display(), solid(0xff0000), XEMBED);
+
+    let (tx, rx) = mpsc::channel();
+    let id = tray
+        .add_menu_item("Click me", move || tx.send(()).unwrap())
+        .unwrap();
+    tray.add_menu_item("Not me", || panic!("wrong item"))
+        .unwrap();
+    let events = tray.events();
+
+    assert_eq!(icon.menu(title), None);
+    icon.click(3);
+    let menu = eventually(|| icon.menu(title));
+    icon.click_menu(menu, 10, 2);
+
+    rx.recv_timeout(Duration::from_secs(2)).unwrap();
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(2)),
+        Ok(TrayEvent::MenuItemActivated(id))
+    );
+    eventually(|| Some(()).filter(|_| icon.menu(title).is_none()));
+}
@@ -0,0 +1,267 @@
+//! A headless X server with a stub `_NET_SYSTEM_TRAY_S0` manager, so the
+//! XEmbed backend can be driven and inspected without a desktop.
+
+use {
+    crate::common::{eventually, start_once, Daemon},
+    std::{
+        env,
+        sync::{mpsc, Arc, Mutex, OnceLock},
+        thread,
+    },
+    x11rb::{
+        connection::Connection,
+        protocol::{
+            xproto::{
+                AtomEnum, ButtonPressEvent, ChangeWindowAttributesAux, ClientMessageEvent,
+                ConfigureWindowAux, ConnectionExt as _, CreateWindowAux, EventMask, ImageFormat,
+                KeyButMask, MapState, Window, WindowClass, BUTTON_PRESS_EVENT,
+            },
+            Event,
+        },
+        rust_connection::RustConnection,
+        COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE,
+    },
+};
+
+/// Width and height the stub tray gives every icon.
+pub const ICON_SIZE: u16 = 24;
+
+// Icon windows the tray embedded, in docking order
+type Docked = Arc<Mutex<Vec<Window>>>;
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        MANAGER,
+        _NET_SYSTEM_TRAY_S0,
+        _NET_SYSTEM_TRAY_OPCODE,
+        _XEMBED_INFO,
+    }
+}
+
+/// Xvfb and the stub tray, started once per test binary.
+pub struct Display {
+    name: String,
+    docked: Docked,
+    _daemon: Daemon,
+}
+
+/// Starts Xvfb and the tray and points `DISPLAY` at them for the trays, see
+/// [`start_once`].
+pub fn display() -> Option<&'static Display> {
+    static DISPLAY: OnceLock<Option<Display>> = OnceLock::new();
+    start_once(&DISPLAY, "Xvfb", || {
+        let display = Display::start()?;
+        // Every test asks for it before making a tray, and the harness itself
+        // always connects to `name`
+        env::set_var("DISPLAY", &display.name);
+        Some(display)
+    })
+}
+
+impl Display {
+    fn start() -> Option<Self> {
+        let (daemon, number) =
+            Daemon::spawn("Xvfb -displayfd 1 -screen 0 640x480x24 -nolisten tcp", &[])?;
+        let name = format!(":{number}");
+        let docked = Docked::default();
+
+        let (tx, rx) = mpsc::channel();
+        let (tray_name, tray_docked) = (name.clone(), Arc::clone(&docked));
+        thread::spawn(move || run_tray(&tray_name, tray_docked, tx));
+        rx.recv().unwrap();
+
+        Some(Self {
+            name,
+            docked,
+            _daemon: daemon,
+        })
+    }
+
+    /// Waits for the icon whose window is named `title` to dock.
+    pub fn find(&self, title: &str) -> Icon {
+        let (conn, screen) = x11rb::connect(Some(&self.name)).unwrap();
+        let root = conn.setup().roots[screen].root;
+        let window = eventually(|| {
+            let docked = self.docked.lock().unwrap().clone();
+            docked
+                .into_iter()
+                .find(|&window| name(&conn, window) == title)
+        });
+
+        Icon { conn, root, window }
+    }
+}
+
+// Embeds every icon that asks into a strip along the bottom of the screen, and
+// maps or unmaps it as its `_XEMBED_INFO` says
+fn run_tray(display: &str, docked: Docked, ready: mpsc::Sender<()>) {
+    let (conn, screen) = x11rb::connect(Some(display)).unwrap();
+    let root = conn.setup().roots[screen].root;
+    let black = conn.setup().roots[screen].black_pixel;
+    let atoms = Atoms::new(&conn).unwrap().reply().unwrap();
+
+    let tray = conn.generate_id().unwrap();
+    conn.create_window(
+        COPY_DEPTH_FROM_PARENT,
+        tray,
+        root,
+        0,
+        480 - ICON_SIZE as i16,
+        640,
+        ICON_SIZE,
+        0,
+        WindowClass::INPUT_OUTPUT,
+        COPY_FROM_PARENT,
+        &CreateWindowAux::new().background_pixel(black),
+    )
+    .unwrap();
+    conn.map_window(tray).unwrap();
+    conn.set_selection_owner(tray, atoms._NET_SYSTEM_TRAY_S0, CURRENT_TIME)
+        .unwrap();
+    let announce = ClientMessageEvent::new(
+        32,
+        root,
+        atoms.MANAGER,
+        [CURRENT_TIME, atoms._NET_SYSTEM_TRAY_S0, tray, 0, 0],
+    );
+    conn.send_event(false, root, EventMask::STRUCTURE_NOTIFY, announce)
+        .unwrap();
+    conn.flush().unwrap();
+    ready.send(()).unwrap();
+
+    loop {
+        match conn.wait_for_event().unwrap() {
+            Event::ClientMessage(e) if e.type_ == atoms._NET_SYSTEM_TRAY_OPCODE => {
+                let [_, opcode, icon, ..] = e.data.as_data32();
+                if opcode != 0 {
+                    continue;
+                }
+                let mut docked = docked.lock().unwrap();
+                let left = (docked.len() as u16 * ICON_SIZE) as i16;
+                conn.change_window_attributes(
+                    icon,
+                    &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+                )
+                .ok();
+                conn.reparent_window(icon, tray, left, 0).ok();
+                conn.configure_window(
+                    icon,
+                    &ConfigureWindowAux::new()
+                        .width(u32::from(ICON_SIZE))
+                        .height(u32::from(ICON_SIZE)),
+                )
+                .ok();
+                follow_flags(&conn, &atoms, icon);
+                docked.push(icon);
+            }
+            Event::PropertyNotify(e) if e.atom == atoms._XEMBED_INFO => {
+                follow_flags(&conn, &atoms, e.window)
+            }
+            _ => (),
+        }
+        conn.flush().unwrap();
+    }
+}
+
+fn follow_flags(conn: &RustConnection, atoms: &Atoms, icon: Window) {
+    let mapped = conn
+        .get_property(false, icon, atoms._XEMBED_INFO, atoms._XEMBED_INFO, 0, 2)
+        .ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .and_then(|reply| reply.value32()?.nth(1))
+        .is_some_and(|flags| flags & 1 != 0);
+    if mapped {
+        conn.map_window(icon).ok();
+    } else {
+        conn.unmap_window(icon).ok();
+    }
+}
+
+/// An icon docked in the stub tray, seen from a separate connection.
+pub struct Icon {
+    conn: RustConnection,
+    root: Window,
+    window: Window,
+}
+
+impl Icon {
+    pub fn is_mapped(&self) -> bool {
+        is_viewable(&self.conn, self.window)
+    }
+
+    /// `0xRRGGBB` at `x`, `y` of the icon window.
+    pub fn pixel(&self, x: i16, y: i16) -> u32 {
+        pixel(&self.conn, self.window, x, y)
+    }
+
+    /// Presses `button` over the icon, like a click the tray passes on.
+    pub fn click(&self, button: u8) {
+        press(&self.conn, self.root, self.window, button, (4, 4));
+    }
+
+    /// The open menu of the icon named `title`, if any.
+    pub fn menu(&self, title: &str) -> Option<Window> {
+        let children = self
+            .conn
+            .query_tree(self.root)
+            .unwrap()
+            .reply()
+            .unwrap()
+            .children;
+        children.into_iter().find(|&window| {
+            let attributes = self.conn.get_window_attributes(window).unwrap().reply();
+            attributes.is_ok_and(|a| a.override_redirect && a.map_state == MapState::VIEWABLE)
+                && name(&self.conn, window) == title
+        })
+    }
+
+    /// Clicks the menu window at `x`, `y`.
+    pub fn click_menu(&self, menu: Window, x: i16, y: i16) {
+        press(&self.conn, self.root, menu, 1, (x, y));
+    }
+}
+
+fn name(conn: &RustConnection, window: Window) -> String {
+    conn.get_property(false, window, AtomEnum::WM_NAME, AtomEnum::STRING, 0, 256)
+        .ok()
+        .and_then(|cookie| cookie.reply().ok())
+        .map(|reply| String::from_utf8_lossy(&reply.value).into_owned())
+        .unwrap_or_default()
+}
+
+fn is_viewable(conn: &RustConnection, window: Window) -> bool {
+    conn.get_window_attributes(window)
+        .unwrap()
+        .reply()
+        .is_ok_and(|attributes| attributes.map_state == MapState::VIEWABLE)
+}
+
+fn pixel(conn: &RustConnection, window: Window, x: i16, y: i16) -> u32 {
+    let image = conn
+        .get_image(ImageFormat::Z_PIXMAP, window, x, y, 1, 1, !0)
+        .unwrap()
+        .reply()
+        .unwrap();
+    u32::from_le_bytes(image.data[..4].try_into().unwrap()) & 0xff_ffff
+}
+
+fn press(conn: &RustConnection, root: Window, window: Window, button: u8, (x, y): (i16, i16)) {
+    let event = ButtonPressEvent {
+        response_type: BUTTON_PRESS_EVENT,
+        detail: button,
+        sequence: 0,
+        time: CURRENT_TIME,
+        root,
+        event: window,
+        child: NONE,
+        root_x: 100 + x,
+        root_y: 200 + y,
+        event_x: x,
+        event_y: y,
+        state: KeyButMask::default(),
+        same_screen: true,
+    };
+    conn.send_event(false, window, EventMask::BUTTON_PRESS, event)
+        .unwrap();
+    conn.flush().unwrap();
+}